@@ -1,10 +1,15 @@
 use eth_encode_packed::abi::encode_packed;
 use eth_encode_packed::ethabi::ethereum_types::{Address, U256};
 use eth_encode_packed::SolidityDataType;
-use ethers::abi::{AbiEncode, Token};
-use ethers::utils::keccak256;
+use ethers::abi::{AbiDecode, AbiEncode, Token};
+use ethers::providers::{HttpClientError, ProviderError};
+use ethers::types::Bytes;
+use ethers::utils::{hex, keccak256};
 use log::debug;
 
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
 struct Sdt<'a>(SolidityDataType<'a>);
 
 impl<'a> TryFrom<&'a Token> for Sdt<'a> {
@@ -35,4 +40,60 @@ pub(crate) fn solidity_keccak256(tokens: &[Token]) -> [u8; 32] {
     let (packed, hash) = encode_packed(tokens.as_slice());
     debug!("Packed hash: {}", hash);
     keccak256(packed)
+}
+
+/// Raw revert data of a failed `eth_call` or `eth_estimateGas`, along with the node's message.
+fn revert_error(error: &ProviderError) -> Option<(Option<Bytes>, &str)> {
+    let error = match error {
+        ProviderError::JsonRpcClientError(error) => match error.downcast_ref::<HttpClientError>() {
+            Some(HttpClientError::JsonRpcError(error)) => error,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let data = error.data.as_ref().and_then(|data| data.as_str()).and_then(|data| data.parse::<Bytes>().ok());
+    Some((data, &error.message))
+}
+
+pub(crate) fn revert_data(error: &ProviderError) -> Option<Bytes> {
+    revert_error(error).and_then(|(data, _)| data)
+}
+
+/// Extracts the revert reason of a failed `eth_call` or `eth_estimateGas`,
+/// `None` means the node itself failed.
+pub(crate) fn revert_reason(error: &ProviderError) -> Option<String> {
+    match revert_error(error)? {
+        (Some(data), _) => Some(decode_revert(&data)),
+        // some nodes only put the reason into the message
+        (None, message) if message.contains("revert") => Some(message.to_string()),
+        (None, _) => None,
+    }
+}
+
+/// Payload of an `Error(string)` revert as raw bytes, Safe's `requiredTxGas`
+/// reverts with the gas amount packed into the string.
+pub(crate) fn revert_bytes(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 4 || data[..4] != ERROR_SELECTOR {
+        return None;
+    }
+    Bytes::decode(&data[4..]).ok().map(|bytes| bytes.to_vec())
+}
+
+/// Decodes `Error(string)` and `Panic(uint256)` revert reasons, custom errors are returned as hex.
+pub(crate) fn decode_revert(data: &[u8]) -> String {
+    if data.len() < 4 {
+        return "reverted without reason".to_string();
+    }
+    let (selector, payload) = data.split_at(4);
+    if selector == ERROR_SELECTOR {
+        if let Ok(reason) = String::decode(payload) {
+            return reason;
+        }
+    }
+    if selector == PANIC_SELECTOR {
+        if let Ok(code) = ethers::types::U256::decode(payload) {
+            return format!("panic code {code:#x}");
+        }
+    }
+    format!("0x{}", hex::encode(data))
 }
\ No newline at end of file
@@ -1,20 +1,30 @@
+use std::collections::HashMap;
 use std::env;
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use actix_cors::Cors;
-use actix_web::{App, middleware};
+use actix_web::{App, HttpMessage, middleware};
+use actix_web::dev::{Service, ServiceResponse};
 use actix_web::HttpServer;
 use actix_web::middleware::Logger;
 use actix_web::web;
 use dotenv::dotenv;
+use ethers::providers::{Http, Provider};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-use crate::safe::{SafeInfo, SafeResponse};
+use crate::safe::{AuthNonce, BatchCall, RelayerHealth, RelayerInfo, RelayState, RelayStatus, SafeFeeQuote, SafeInfo, SafeModules, SafeResponse, SafeState, SafeTxHash, SafeTxPayload};
+use crate::safe_auth::SiweAuth;
 use crate::safe_config::SafeConfig;
+use crate::safe_contracts::print_code_hashes;
 use crate::safe_handlers::*;
 use crate::safe_service::SafeService;
-use crate::safe_use_case::SafeUseCase;
+use crate::safe_rate_limit::{RateLimiter, RateLimits};
+use crate::safe_sponsor::SponsorQuota;
+use crate::safe_tenant::Tenants;
+use crate::safe_use_case::{SafeType, SafeUseCase};
 
 pub(crate) mod safe_service;
 pub(crate) mod safe_handlers;
@@ -22,14 +32,29 @@ pub(crate) mod safe;
 pub(crate) mod safe_use_case;
 pub(crate) mod safe_config;
 pub(crate) mod ethers_ext;
+pub(crate) mod safe_tx;
+pub(crate) mod safe_tracker;
+pub(crate) mod safe_relayer;
+pub(crate) mod safe_refund;
+pub(crate) mod safe_quote;
+pub(crate) mod safe_sponsor;
+pub(crate) mod safe_auth;
+pub(crate) mod safe_tenant;
+pub(crate) mod safe_rate_limit;
+pub(crate) mod safe_contracts;
+pub(crate) mod safe_multi_send;
+pub(crate) mod safe_multicall;
 
 #[derive(OpenApi)]
 #[
 
-openapi(paths(calculate_address, deploy_contract, exec_transaction),
-components(schemas(SafeInfo, SafeCall, SafeResponse, SafeErr)),
+openapi(paths(calculate_address, safe_state, list_safes, deploy_contract, exec_transaction, transaction_hash, estimate_fee, add_owner, remove_owner, swap_owner, change_threshold,
+list_modules, enable_module, disable_module, auth_nonce, relay_status, health),
+components(schemas(AuthNonce, SafeInfo, SafeState, SafeCall, SafeTxParams, SafeDeploy, SafeEstimate, SafeResponse, SafeTxHash, SafeFeeQuote,
+AddOwner, RemoveOwner, SwapOwner, ChangeThreshold, SafeTxPayload, SafeModules, ModuleParams, BatchCall,
+RelayState, RelayStatus, RelayerHealth, RelayerInfo, SafeErr)),
 tags(
-(name = "safe::api", description = "Safe management endpoints.")
+(name = "safe::api", description = "Safe management endpoints, for another chain than the default one prefix them with `/v1/{chainId}`.")
 ))
 ]
 struct ApiDoc;
@@ -42,40 +67,124 @@ async fn main() -> std::io::Result<()> {
     env::set_var("RUST_BACKTRACE", "full");
 
     env_logger::init_from_env(env_logger::Env::new());
-    let safe_use_case = SafeUseCase::new(
-        Arc::new(SafeService::new(SafeConfig::new()).await)
-    );
 
-    let address = env::var("ADDRESS")
-        .expect("ADDRESS must be defined");
+    // `--config <path>` or `CONFIG_FILE` points to a TOML file, `--check-config` only validates it,
+    // `--print-code-hashes <rpc url>` prints the code hashes of the canonical deployments on that chain
+    let args: Vec<String> = env::args().collect();
+    let check_config = args.iter().any(|arg| arg == "--check-config");
+    // before loading the configuration, whose contract check needs the hashes
+    if let Some(rpc_url) = args.windows(2).find(|args| args[0] == "--print-code-hashes").map(|args| &args[1]) {
+        let code_hashes = match Provider::<Http>::try_from(rpc_url.as_str()) {
+            Ok(provider) => print_code_hashes(&provider).await,
+            Err(e) => Err(format!("{rpc_url} is not a URL: {e}")),
+        };
+        match code_hashes {
+            Ok(code_hashes) => println!("{code_hashes}"),
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let config_file = args.windows(2)
+        .find(|args| args[0] == "--config")
+        .map(|args| args[1].clone())
+        .or_else(|| env::var("CONFIG_FILE").ok());
+    let safe_config = match SafeConfig::load(config_file.as_deref()).await {
+        Ok(safe_config) => safe_config,
+        Err(errors) => {
+            eprintln!("{errors}");
+            process::exit(1);
+        }
+    };
+    if check_config {
+        println!("Configuration is valid");
+        return Ok(());
+    }
 
-    let port = env::var("PORT")
-        .map(|port| port.parse::<u16>())
-        .unwrap()
-        .expect("PORT must be defined");
+    let tenants = Arc::new(Tenants::new(
+        safe_config.tenants.as_deref(),
+        SponsorQuota {
+            per_address: safe_config.sponsor_deploys_per_address,
+            per_ip: safe_config.sponsor_deploys_per_ip,
+            total: safe_config.sponsor_deploys_per_day,
+        },
+    ).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    }));
+    let rate_limiter = Arc::new(RateLimiter::new(RateLimits {
+        read: safe_config.rate_limit_read,
+        deploy: safe_config.rate_limit_deploy,
+        exec: safe_config.rate_limit_exec,
+    }));
+    let auth = SiweAuth::new(safe_config.siwe_domain.clone(), Duration::from_secs(safe_config.auth_nonce_ttl));
+
+    let mut chains: HashMap<u64, SafeType> = HashMap::new();
+    let default_service = SafeService::new(safe_config.clone()).await.unwrap_or_else(|errors| {
+        eprintln!("{errors}");
+        process::exit(1);
+    });
+    let default_chain_id = default_service.chain_id().as_u64();
+    chains.insert(default_chain_id, Arc::new(default_service));
+    for chain in &safe_config.chains {
+        let service = SafeService::new(safe_config.for_chain(chain)).await.unwrap_or_else(|errors| {
+            eprintln!("{errors}");
+            process::exit(1);
+        });
+        chains.insert(chain.chain_id, Arc::new(service));
+    }
+    let safe_use_case = SafeUseCase::new(chains, default_chain_id, auth);
+    let (address, port) = (safe_config.address.clone(), safe_config.port);
 
     HttpServer::new(move || {
+        let tenants = tenants.clone();
+        let rate_limiter = rate_limiter.clone();
+        let cors = cors(tenants.allowed_origins());
         App::new()
+            .wrap_fn(move |req, srv| {
+                let res = match tenants.admit(req.request(), &rate_limiter) {
+                    Ok(tenant) => {
+                        if let Some(tenant) = tenant {
+                            req.extensions_mut().insert(tenant);
+                        }
+                        Ok(srv.call(req))
+                    }
+                    Err(e) => Err(req.error_response(e)),
+                };
+                async move {
+                    match res {
+                        Ok(res) => res.await.map(ServiceResponse::map_into_left_body),
+                        Err(res) => Ok(res.map_into_right_body()),
+                    }
+                }
+            })
             .wrap(Logger::default())
             .wrap(middleware::Compress::default())
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header()
-                    .supports_credentials()
-                    .max_age(3600),
-            )
+            .wrap(cors)
             .app_data(web::Data::new(safe_use_case.clone()))
             .service(
                 SwaggerUi::new("/swagger/{_:.*}")
                     .url("/api-doc/openapi.json", ApiDoc::openapi()),
             )
-            .service(calculate_address)
-            .service(deploy_contract)
-            .service(exec_transaction)
+            // chain ids are numeric, so `/v1/safe/...` falls through to the default chain
+            .service(web::scope("/v1/{chain_id:\\d+}").configure(routes))
+            .service(web::scope("/v1").configure(routes))
     })
         .bind((address, port))?
         .run()
         .await
+}
+
+/// API keys travel in a header, so browsers never need to send credentials.
+fn cors(allowed_origins: Option<Vec<String>>) -> Cors {
+    let cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .max_age(3600);
+    match allowed_origins {
+        Some(allowed_origins) => allowed_origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin)),
+        None => cors.allow_any_origin(),
+    }
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::fmt::{Display, Formatter};
 
 use async_trait::async_trait;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 #[derive(Serialize, ToSchema)]
@@ -9,13 +9,175 @@ use utoipa::ToSchema;
 pub(crate) struct SafeInfo {
     pub(crate) address: String,
     pub(crate) is_deployed: bool,
+    pub(crate) owners: Vec<String>,
+    pub(crate) threshold: usize,
+    pub(crate) salt_nonce: String,
+}
+
+/// On-chain state of a deployed Safe.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeState {
+    pub(crate) address: String,
+    pub(crate) owners: Vec<String>,
+    pub(crate) threshold: usize,
+    pub(crate) nonce: String,
+    pub(crate) modules: Vec<String>,
+    pub(crate) version: String,
+    pub(crate) fallback_handler: String,
+    pub(crate) balance: String,
+}
+
+/// A page of the modules enabled on a Safe, `next` starts the following page.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeModules {
+    pub(crate) modules: Vec<String>,
+    pub(crate) next: Option<String>,
+}
+
+/// Parameters the counterfactual Safe is created with.
+/// Empty `owners` means the user is the only owner, missing `salt_nonce`
+/// and `fallback_handler` fall back to the configured ones.
+#[derive(Clone, Default)]
+pub(crate) struct SafeSetup {
+    pub(crate) owners: Vec<String>,
+    pub(crate) threshold: Option<usize>,
+    pub(crate) salt_nonce: Option<String>,
+    pub(crate) fallback_handler: Option<String>,
 }
 
 #[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SafeResponse {
-    pub(crate) block_hash: String,
+    pub(crate) relay_id: String,
+    pub(crate) transaction_hash: String,
+    // the calls of a batch, as unpacked from the MultiSend calldata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) calls: Option<Vec<BatchCall>>,
+}
+
+/// One call of a batch run through MultiSend.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BatchCall {
+    pub(crate) to: String,
+    pub(crate) value: String,
+    #[serde(default)]
+    pub(crate) data: Vec<u8>,
+    #[serde(default)]
+    pub(crate) operation: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum RelayState {
+    Pending,
+    Mined,
+    Failed,
+    Replaced,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RelayStatus {
+    pub(crate) id: String,
+    pub(crate) status: RelayState,
     pub(crate) transaction_hash: String,
+    pub(crate) transaction_hashes: Vec<String>,
+    pub(crate) confirmations: u64,
+    pub(crate) block_hash: Option<String>,
+    pub(crate) block_number: Option<u64>,
+    pub(crate) gas_used: Option<String>,
+    // stuck with fees at GAS_BUMP_MAX_FEE, waiting without further bumps
+    pub(crate) fee_capped: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeTxHash {
+    pub(crate) safe_tx_hash: String,
+    #[schema(value_type = Object)]
+    pub(crate) typed_data: serde_json::Value,
+    pub(crate) nonce: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) calls: Option<Vec<BatchCall>>,
+}
+
+/// Refund parameters the relayer accepts for a transaction, until `expires_at` (unix seconds).
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeFeeQuote {
+    pub(crate) safe_tx_gas: String,
+    pub(crate) base_gas: String,
+    pub(crate) gas_price: String,
+    pub(crate) gas_token: String,
+    pub(crate) refund_receiver: String,
+    pub(crate) expires_at: u64,
+}
+
+/// A quoted transaction of a Safe, ready to be signed by its owners and relayed through exec.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeTxPayload {
+    pub(crate) to: String,
+    pub(crate) value: String,
+    pub(crate) data: Vec<u8>,
+    pub(crate) operation: u8,
+    pub(crate) safe_tx_gas: String,
+    pub(crate) base_gas: String,
+    pub(crate) gas_price: String,
+    pub(crate) gas_token: String,
+    pub(crate) refund_receiver: String,
+    pub(crate) nonce: String,
+    pub(crate) safe_tx_hash: String,
+    #[schema(value_type = Object)]
+    pub(crate) typed_data: serde_json::Value,
+    pub(crate) expires_at: u64,
+    // the owner preceding the removed or swapped one in the Safe's linked list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) prev_owner: Option<String>,
+    // the module preceding the disabled one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) prev_module: Option<String>,
+}
+
+/// Owner management call of a Safe on itself. A missing threshold keeps the current one,
+/// lowered to the remaining owners on removal.
+pub(crate) enum OwnerChange {
+    Add { owner: String, threshold: Option<usize> },
+    Remove { owner: String, threshold: Option<usize> },
+    Swap { old_owner: String, new_owner: String },
+    ChangeThreshold { threshold: usize },
+}
+
+/// Module management call of a Safe on itself.
+pub(crate) enum ModuleChange {
+    Enable { module: String },
+    Disable { module: String },
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AuthNonce {
+    pub(crate) nonce: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RelayerInfo {
+    pub(crate) address: String,
+    pub(crate) balance: String,
+    pub(crate) in_flight: usize,
+    pub(crate) funded: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RelayerHealth {
+    pub(crate) accepting_relays: bool,
+    pub(crate) min_balance: String,
+    pub(crate) relayers: Vec<RelayerInfo>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +186,17 @@ pub(crate) enum SafeError {
     NotDeployed,
     BadAddress(String),
     BadParams(String),
+    BadSignatures(String),
+    ExecutionReverted(String),
+    UnknownRelay(String),
+    UnsupportedChain(u64),
+    RelayerUnderfunded,
+    RefundRejected(String),
+    Unauthorized(String),
+    Forbidden(String),
+    QuotaExceeded(String),
+    // seconds until the next request is allowed
+    RateLimited(u64),
     RpcError(String),
 }
 
@@ -34,6 +207,16 @@ impl Display for SafeError {
             SafeError::NotDeployed => write!(f, "Safe is not deployed"),
             SafeError::BadAddress(e) => write!(f, "Invalid address: {}", e),
             SafeError::BadParams(e) => write!(f, "Bad parameters passed: {}", e),
+            SafeError::BadSignatures(e) => write!(f, "Invalid signatures: {}", e),
+            SafeError::ExecutionReverted(e) => write!(f, "Execution reverted: {}", e),
+            SafeError::UnknownRelay(e) => write!(f, "Relay not found: {}", e),
+            SafeError::UnsupportedChain(e) => write!(f, "Chain {} is not supported", e),
+            SafeError::RelayerUnderfunded => write!(f, "Relayer balance is too low to pay for gas"),
+            SafeError::RefundRejected(e) => write!(f, "Gas refund not accepted: {}", e),
+            SafeError::Unauthorized(e) => write!(f, "Unauthorized: {}", e),
+            SafeError::Forbidden(e) => write!(f, "Forbidden: {}", e),
+            SafeError::QuotaExceeded(e) => write!(f, "Quota exceeded: {}", e),
+            SafeError::RateLimited(e) => write!(f, "Too many requests, retry in {} seconds", e),
             SafeError::RpcError(e) => write!(f, "Rpc unavailable: {}", e)
         }
     }
@@ -43,21 +226,88 @@ impl std::error::Error for SafeError {}
 
 #[async_trait]
 pub(crate) trait Safe {
-    async fn info(&self, user_address: &str) -> Result<SafeInfo, SafeError>;
+    async fn info(&self, user_address: &str, setup: &SafeSetup) -> Result<SafeInfo, SafeError>;
+
+    async fn list(&self, user_address: &str, setup: &SafeSetup, from: u64, count: u64) -> Result<Vec<SafeInfo>, SafeError>;
+
+    async fn state(&self, user_address: &str, setup: &SafeSetup) -> Result<SafeState, SafeError>;
 
-    async fn deploy(&self, user_address: &str) -> Result<SafeResponse, SafeError>;
+    async fn modules(&self, user_address: &str, setup: &SafeSetup, start: Option<&str>, count: u64) -> Result<SafeModules, SafeError>;
+
+    async fn deploy(&self, user_address: &str, setup: &SafeSetup) -> Result<SafeResponse, SafeError>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn tx_hash(&self,
+                     user_address: &str,
+                     setup: &SafeSetup,
+                     to: &str,
+                     value: &str,
+                     data: Vec<u8>,
+                     operation: u8,
+                     calls: &[BatchCall],
+                     safe_tx_gas: &str,
+                     base_gas: &str,
+                     gas_price: &str,
+                     gas_token: &str,
+                     refund_receiver: &str) -> Result<SafeTxHash, SafeError>;
 
     #[allow(clippy::too_many_arguments)]
     async fn exec(&self,
                   user_address: &str,
+                  setup: &SafeSetup,
                   to: &str,
                   value: &str,
                   data: Vec<u8>,
                   operation: u8,
+                  calls: &[BatchCall],
                   safe_tx_gas: &str,
                   base_gas: &str,
                   gas_price: &str,
                   gas_token: &str,
                   refund_receiver: &str,
                   signatures: Vec<u8>) -> Result<SafeResponse, SafeError>;
+
+    /// Deploys the Safe and executes the transaction on nonce 0 of the new Safe, both in one multicall.
+    #[allow(clippy::too_many_arguments)]
+    async fn deploy_exec(&self,
+                         user_address: &str,
+                         setup: &SafeSetup,
+                         to: &str,
+                         value: &str,
+                         data: Vec<u8>,
+                         operation: u8,
+                         calls: &[BatchCall],
+                         safe_tx_gas: &str,
+                         base_gas: &str,
+                         gas_price: &str,
+                         gas_token: &str,
+                         refund_receiver: &str,
+                         signatures: Vec<u8>) -> Result<SafeResponse, SafeError>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn estimate(&self,
+                      user_address: &str,
+                      setup: &SafeSetup,
+                      to: &str,
+                      value: &str,
+                      data: Vec<u8>,
+                      operation: u8,
+                      calls: &[BatchCall],
+                      gas_token: &str) -> Result<SafeFeeQuote, SafeError>;
+
+    async fn owner_tx(&self,
+                      user_address: &str,
+                      setup: &SafeSetup,
+                      change: &OwnerChange,
+                      gas_token: &str) -> Result<SafeTxPayload, SafeError>;
+
+    async fn module_tx(&self,
+                       user_address: &str,
+                       setup: &SafeSetup,
+                       change: &ModuleChange,
+                       gas_token: &str) -> Result<SafeTxPayload, SafeError>;
+
+    async fn relay(&self, relay_id: &str) -> Result<RelayStatus, SafeError>;
+
+    async fn health(&self) -> Result<RelayerHealth, SafeError>;
 }
\ No newline at end of file
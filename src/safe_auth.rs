@@ -0,0 +1,420 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ethers::core::rand;
+use ethers::types::{Address, Signature, U256};
+use ethers::utils::hex;
+
+use crate::safe::SafeError;
+
+const PREAMBLE: &str = " wants you to sign in with your Ethereum account:";
+
+// fields in the order EIP-4361 lays them out
+const FIELDS: [&str; 9] = ["URI", "Version", "Chain ID", "Nonce", "Issued At", "Expiration Time", "Not Before", "Request ID", "Resources"];
+
+// nonces are handed out to anyone, so the oldest are dropped beyond this many
+const MAX_ISSUED_NONCES: usize = 10_000;
+
+/// Fields of an EIP-4361 message the relayer checks.
+struct SiweMessage {
+    domain: String,
+    address: Address,
+    uri: String,
+    version: String,
+    chain_id: U256,
+    nonce: String,
+    expiration_time: Option<u64>,
+    not_before: Option<u64>,
+}
+
+/// Sign-In with Ethereum challenges proving the caller controls the user address.
+pub(crate) struct SiweAuth {
+    domain: String,
+    nonce_ttl: Duration,
+    nonces: Mutex<IssuedNonces>,
+}
+
+/// Issued and not yet used nonces, with the order they were issued in.
+#[derive(Default)]
+struct IssuedNonces {
+    issued_at: HashMap<String, Instant>,
+    // oldest first, may still hold nonces used since
+    order: VecDeque<String>,
+}
+
+impl SiweAuth {
+    pub(crate) fn new(domain: String, nonce_ttl: Duration) -> Self {
+        Self {
+            domain,
+            nonce_ttl,
+            nonces: Mutex::new(IssuedNonces::default()),
+        }
+    }
+
+    pub(crate) fn nonce(&self) -> String {
+        let nonce = hex::encode(rand::random::<[u8; 16]>());
+        let mut nonces = self.nonces.lock().unwrap();
+        while let Some(oldest) = nonces.order.front() {
+            let expired = match nonces.issued_at.get(oldest) {
+                Some(issued_at) => issued_at.elapsed() >= self.nonce_ttl,
+                None => true,
+            };
+            if !expired && nonces.order.len() < MAX_ISSUED_NONCES {
+                break;
+            }
+            if let Some(oldest) = nonces.order.pop_front() {
+                nonces.issued_at.remove(&oldest);
+            }
+        }
+        nonces.issued_at.insert(nonce.clone(), Instant::now());
+        nonces.order.push_back(nonce.clone());
+        nonce
+    }
+
+    /// Checks `message` was signed by `user_address` for this relayer on `chain_id`, using up its nonce.
+    pub(crate) fn verify(&self, user_address: &str, chain_id: u64, message: &str, signature: &str) -> Result<(), SafeError> {
+        let user_address = user_address.parse::<Address>()
+            .map_err(|e| SafeError::BadAddress(format!("to {e}")))?;
+        let siwe = parse_message(message)?;
+
+        if siwe.domain != self.domain {
+            return Err(SafeError::Unauthorized(format!("message is for {}, not {}", siwe.domain, self.domain)));
+        }
+        if uri_authority(&siwe.uri) != self.domain {
+            return Err(SafeError::Unauthorized(format!("message is for {}, not {}", siwe.uri, self.domain)));
+        }
+        if siwe.address != user_address {
+            return Err(SafeError::Unauthorized(format!("message is for {:?}, not {user_address:?}", siwe.address)));
+        }
+        if siwe.version != "1" {
+            return Err(SafeError::Unauthorized(format!("unsupported message version {}", siwe.version)));
+        }
+        if siwe.chain_id != U256::from(chain_id) {
+            return Err(SafeError::Unauthorized(format!("message is for chain {}, not {chain_id}", siwe.chain_id)));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if matches!(siwe.expiration_time, Some(expiration_time) if expiration_time <= now) {
+            return Err(SafeError::Unauthorized("message has expired".to_string()));
+        }
+        if matches!(siwe.not_before, Some(not_before) if not_before > now) {
+            return Err(SafeError::Unauthorized("message is not valid yet".to_string()));
+        }
+
+        let signature = signature.parse::<Signature>()
+            .map_err(|e| SafeError::Unauthorized(format!("bad signature: {e}")))?;
+        let signer = signature.recover(message)
+            .map_err(|e| SafeError::Unauthorized(format!("bad signature: {e}")))?;
+        if signer != user_address {
+            return Err(SafeError::Unauthorized(format!("message is signed by {signer:?}, not {user_address:?}")));
+        }
+
+        // the nonce is only spent by a valid message, so a bad attempt does not burn it
+        match self.nonces.lock().unwrap().issued_at.remove(&siwe.nonce) {
+            Some(issued_at) if issued_at.elapsed() < self.nonce_ttl => Ok(()),
+            _ => Err(SafeError::Unauthorized(format!("nonce {} is unknown, used or expired", siwe.nonce))),
+        }
+    }
+}
+
+fn parse_message(message: &str) -> Result<SiweMessage, SafeError> {
+    let bad_message = |e: &str| SafeError::Unauthorized(format!("bad sign-in message: {e}"));
+
+    let mut lines = message.lines();
+    let domain = lines.next()
+        .and_then(|line| line.strip_suffix(PREAMBLE))
+        .ok_or_else(|| bad_message("missing preamble"))?;
+    let address = lines.next()
+        .and_then(|line| line.parse::<Address>().ok())
+        .ok_or_else(|| bad_message("missing address"))?;
+
+    let mut fields = HashMap::new();
+    let mut last = None;
+    for (name, value) in lines.filter_map(|line| line.split_once(": ")) {
+        let at = match FIELDS.iter().position(|field| *field == name) {
+            Some(at) => at,
+            // a statement may hold ": " as well
+            None => continue,
+        };
+        if matches!(last, Some(last) if last >= at) {
+            return Err(bad_message(&format!("{name} out of order")));
+        }
+        last = Some(at);
+        fields.insert(name, value);
+    }
+    let field = |name: &str| fields.get(name).copied().ok_or_else(|| bad_message(&format!("missing {name}")));
+    let timestamp = |name: &str| fields.get(name)
+        .map(|value| parse_timestamp(value).ok_or_else(|| bad_message(&format!("bad {name}"))))
+        .transpose();
+
+    Ok(SiweMessage {
+        domain: domain.to_string(),
+        address,
+        uri: field("URI")?.to_string(),
+        version: field("Version")?.to_string(),
+        chain_id: U256::from_dec_str(field("Chain ID")?).map_err(|_| bad_message("bad Chain ID"))?,
+        nonce: field("Nonce")?.to_string(),
+        expiration_time: timestamp("Expiration Time")?,
+        not_before: timestamp("Not Before")?,
+    })
+}
+
+/// Parses an RFC 3339 timestamp such as `2021-12-07T18:28:18.807Z` into unix seconds.
+/// The authority of `uri`, such as `example.com:8080` of `https://example.com:8080/login`.
+fn uri_authority(uri: &str) -> &str {
+    let uri = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+    uri.split(['/', '?', '#']).next().unwrap_or(uri)
+}
+
+fn parse_timestamp(value: &str) -> Option<u64> {
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let (time, offset) = match time.strip_suffix('Z').or_else(|| time.strip_suffix('z')) {
+        Some(time) => (time, 0),
+        None => {
+            let at = time.rfind(['+', '-'])?;
+            let (time, offset) = time.split_at(at);
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+            if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+                return None;
+            }
+            (time, sign * (hours * 3600 + minutes * 60))
+        }
+    };
+    // fractional seconds do not matter at this precision
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    // a leap second is let through as the next second
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month)
+        || !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..=60).contains(&second) {
+        return None;
+    }
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400 + hour * 3600 + minute * 60 + second - offset).ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::core::k256::ecdsa::SigningKey;
+    use ethers::core::k256::SecretKey;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::utils::hash_message;
+
+    use super::*;
+
+    const DOMAIN: &str = "relay.example.com";
+
+    fn wallet() -> LocalWallet {
+        LocalWallet::from(SigningKey::from(SecretKey::from_be_bytes(&[1; 32]).unwrap()))
+    }
+
+    fn message(address: Address, nonce: &str, extra: &str) -> String {
+        format!("{DOMAIN}{PREAMBLE}\n{}\n\nSign in to the relay\n\nURI: https://{DOMAIN}/login\nVersion: 1\nChain ID: 5\nNonce: {nonce}\nIssued At: 2021-12-07T18:28:18.807Z{extra}",
+                ethers::utils::to_checksum(&address, None))
+    }
+
+    fn sign(wallet: &LocalWallet, message: &str) -> String {
+        wallet.sign_hash(hash_message(message)).to_string()
+    }
+
+    fn unauthorized(result: Result<(), SafeError>) -> String {
+        match result {
+            Err(SafeError::Unauthorized(message)) => message,
+            other => panic!("expected Unauthorized, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn messages_parse() {
+        let address = wallet().address();
+        let siwe = parse_message(&message(address, "abc", "\nExpiration Time: 2021-12-07T18:28:18Z\nNot Before: 2021-12-07T17:28:18Z")).unwrap();
+        assert_eq!(siwe.domain, DOMAIN);
+        assert_eq!(siwe.address, address);
+        assert_eq!(siwe.uri, format!("https://{DOMAIN}/login"));
+        assert_eq!(siwe.version, "1");
+        assert_eq!(siwe.chain_id, U256::from(5));
+        assert_eq!(siwe.nonce, "abc");
+        assert_eq!(siwe.expiration_time, Some(1_638_901_698));
+        assert_eq!(siwe.not_before, Some(1_638_898_098));
+    }
+
+    #[test]
+    fn bad_messages_are_refused() {
+        let message = message(wallet().address(), "abc", "");
+        let cases = [
+            ("missing preamble", message.replacen(PREAMBLE, "", 1)),
+            ("missing address", message.lines().filter(|line| !line.starts_with("0x")).collect::<Vec<_>>().join("\n")),
+            ("missing URI", message.replace("URI: ", "Uri: ")),
+            ("missing Version", message.replace("Version: 1\n", "")),
+            ("missing Chain ID", message.replace("Chain ID: 5\n", "")),
+            ("missing Nonce", message.replace("Nonce: abc\n", "")),
+            ("bad Chain ID", message.replace("Chain ID: 5", "Chain ID: five")),
+            ("bad Expiration Time", format!("{message}\nExpiration Time: 2021-02-30T00:00:00Z")),
+            // the address ahead of the preamble
+            ("missing preamble", {
+                let mut lines = message.lines().collect::<Vec<_>>();
+                lines.swap(0, 1);
+                lines.join("\n")
+            }),
+            ("Version out of order", message.replace("Version: 1\nChain ID: 5", "Chain ID: 5\nVersion: 1")),
+            ("Nonce out of order", format!("{message}\nNonce: def")),
+        ];
+        for (error, message) in cases {
+            match parse_message(&message) {
+                Err(SafeError::Unauthorized(message)) => assert_eq!(message, format!("bad sign-in message: {error}")),
+                other => panic!("{error}: expected Unauthorized, got {:?}", other.map(|siwe| siwe.nonce)),
+            }
+        }
+    }
+
+    #[test]
+    fn uri_authorities() {
+        let cases = [
+            ("https://relay.example.com/login", "relay.example.com"),
+            ("https://relay.example.com:8080", "relay.example.com:8080"),
+            ("https://relay.example.com?next=/", "relay.example.com"),
+            ("https://relay.example.com.evil.com/relay.example.com", "relay.example.com.evil.com"),
+            ("relay.example.com/login", "relay.example.com"),
+        ];
+        for (uri, authority) in cases {
+            assert_eq!(uri_authority(uri), authority, "{uri}");
+        }
+    }
+
+    #[test]
+    fn timestamps_parse() {
+        let cases = [
+            ("1970-01-01T00:00:00Z", Some(0)),
+            ("2021-12-07T18:28:18Z", Some(1_638_901_698)),
+            ("2021-12-07t18:28:18z", None),
+            ("2021-12-07T18:28:18.807Z", Some(1_638_901_698)),
+            ("2021-12-07T18:28:18.807z", Some(1_638_901_698)),
+            ("2021-12-07T20:28:18+02:00", Some(1_638_901_698)),
+            ("2021-12-07T13:58:18.5-04:30", Some(1_638_901_698)),
+            ("2020-02-29T00:00:00Z", Some(1_582_934_400)),
+            ("2000-02-29T00:00:00Z", Some(951_782_400)),
+            ("2021-02-29T00:00:00Z", None),
+            ("1900-02-29T00:00:00Z", None),
+            ("2021-02-30T00:00:00Z", None),
+            ("2021-04-31T00:00:00Z", None),
+            ("2021-13-01T00:00:00Z", None),
+            ("2021-00-01T00:00:00Z", None),
+            ("2021-12-00T00:00:00Z", None),
+            ("2021-12-07T24:00:00Z", None),
+            ("2021-12-07T18:60:00Z", None),
+            ("2021-12-07T18:28:61Z", None),
+            ("2021-12-07T18:28:18+24:00", None),
+            ("2021-12-07T18:28:18", None),
+            ("2021-12-07 18:28:18Z", None),
+            ("2021-12-07T18:28Z", None),
+            ("1969-12-31T23:59:59Z", None),
+        ];
+        for (value, timestamp) in cases {
+            assert_eq!(parse_timestamp(value), timestamp, "{value}");
+        }
+    }
+
+    #[test]
+    fn signed_messages_verify_once() {
+        let auth = SiweAuth::new(DOMAIN.to_string(), Duration::from_secs(300));
+        let wallet = wallet();
+        let user_address = format!("{:?}", wallet.address());
+        let nonce = auth.nonce();
+        let message = message(wallet.address(), &nonce, "");
+
+        assert!(auth.verify(&user_address, 5, &message, &sign(&wallet, &message)).is_ok());
+        assert_eq!(unauthorized(auth.verify(&user_address, 5, &message, &sign(&wallet, &message))),
+                   format!("nonce {nonce} is unknown, used or expired"));
+    }
+
+    #[test]
+    fn bad_sign_ins_are_refused_without_using_the_nonce() {
+        let auth = SiweAuth::new(DOMAIN.to_string(), Duration::from_secs(300));
+        let wallet = wallet();
+        let user_address = format!("{:?}", wallet.address());
+        let nonce = auth.nonce();
+        let message = message(wallet.address(), &nonce, "");
+        let other = LocalWallet::from(SigningKey::from(SecretKey::from_be_bytes(&[2; 32]).unwrap()));
+
+        let cases = [
+            ("message is for chain 5, not 1", 1, message.clone(), None),
+            ("message is for evil.com, not relay.example.com", 5, message.replace(&format!("{DOMAIN}{PREAMBLE}"), &format!("evil.com{PREAMBLE}")), None),
+            ("message is for https://evil.com/login, not relay.example.com", 5, message.replace(&format!("https://{DOMAIN}"), "https://evil.com"), None),
+            ("unsupported message version 2", 5, message.replace("Version: 1", "Version: 2"), None),
+            ("message has expired", 5, format!("{message}\nExpiration Time: 2021-12-07T18:28:18Z"), None),
+            ("message is not valid yet", 5, format!("{message}\nNot Before: 2999-01-01T00:00:00Z"), None),
+            ("message is signed by", 5, message.clone(), Some(&other)),
+        ];
+        for (error, chain_id, message, signer) in cases {
+            let signature = sign(signer.unwrap_or(&wallet), &message);
+            let result = unauthorized(auth.verify(&user_address, chain_id, &message, &signature));
+            assert!(result.starts_with(error), "{error}: {result}");
+        }
+        let other_address = format!("{:?}", other.address());
+        assert!(unauthorized(auth.verify(&other_address, 5, &message, &sign(&other, &message))).starts_with("message is for 0x"));
+
+        assert!(auth.verify(&user_address, 5, &message, &sign(&wallet, &message)).is_ok());
+    }
+
+    #[test]
+    fn unknown_and_expired_nonces_are_refused() {
+        let wallet = wallet();
+        let user_address = format!("{:?}", wallet.address());
+
+        let auth = SiweAuth::new(DOMAIN.to_string(), Duration::from_secs(300));
+        let message = message(wallet.address(), "00112233445566778899aabbccddeeff", "");
+        assert_eq!(unauthorized(auth.verify(&user_address, 5, &message, &sign(&wallet, &message))),
+                   "nonce 00112233445566778899aabbccddeeff is unknown, used or expired");
+
+        let auth = SiweAuth::new(DOMAIN.to_string(), Duration::ZERO);
+        let message = self::message(wallet.address(), &auth.nonce(), "");
+        assert!(unauthorized(auth.verify(&user_address, 5, &message, &sign(&wallet, &message))).ends_with("is unknown, used or expired"));
+    }
+
+    #[test]
+    fn issued_nonces_are_evicted() {
+        let auth = SiweAuth::new(DOMAIN.to_string(), Duration::from_secs(300));
+        let first = auth.nonce();
+        let second = auth.nonce();
+        for _ in 2..MAX_ISSUED_NONCES {
+            auth.nonce();
+        }
+        assert!(auth.nonces.lock().unwrap().issued_at.contains_key(&first));
+        auth.nonce();
+        {
+            let nonces = auth.nonces.lock().unwrap();
+            assert_eq!(nonces.issued_at.len(), MAX_ISSUED_NONCES);
+            assert!(!nonces.issued_at.contains_key(&first));
+            assert!(nonces.issued_at.contains_key(&second));
+        }
+
+        let auth = SiweAuth::new(DOMAIN.to_string(), Duration::ZERO);
+        auth.nonce();
+        let last = auth.nonce();
+        let nonces = auth.nonces.lock().unwrap();
+        assert_eq!(nonces.order, VecDeque::from([last.clone()]));
+        assert_eq!(nonces.issued_at.keys().collect::<Vec<_>>(), [&last]);
+    }
+}
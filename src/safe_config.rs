@@ -1,36 +1,754 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::str::FromStr;
+
+use ethers::core::k256::SecretKey;
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Address, U256};
+use ethers::utils::{hex, to_checksum};
+use serde::de::DeserializeOwned;
+use log::warn;
+use serde::Deserialize;
+use toml::Value;
+
+use crate::safe_contracts::{ContractCheck, ContractKind, parse_code_hashes, verify_contracts};
+use crate::safe_quote::StaticPriceTable;
+use crate::safe_refund::RefundPolicy;
+use crate::safe_relayer::RelayerStrategy;
+use crate::safe_tracker::MIN_BUMP_PERCENT;
+
+/// Daily sponsored deployments of a tenant, missing ones default to the global quotas.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SponsorshipConfig {
+    pub(crate) per_address: Option<u32>,
+    pub(crate) per_ip: Option<u32>,
+    pub(crate) per_day: Option<u32>,
+}
+
+/// One entry of the `TENANTS_FILE` JSON array or of the `[[tenants]]` tables of the config file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TenantConfig {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) api_keys: Vec<String>,
+    #[serde(default)]
+    pub(crate) allowed_origins: Vec<String>,
+    pub(crate) requests_per_minute: Option<u32>,
+    pub(crate) sponsorship: Option<SponsorshipConfig>,
+    pub(crate) fallback_address: Option<String>,
+    pub(crate) salt_nonce: Option<String>,
+}
+
+/// One entry of the `CHAINS_FILE` JSON array or of the `[[chains]]` tables of the config file,
+/// missing settings are taken from the default chain.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ChainConfig {
+    pub(crate) chain_id: u64,
+    pub(crate) rpc_url: String,
+    pub(crate) proxy_factory_address: String,
+    pub(crate) master_copy_address: String,
+    pub(crate) fallback_address: String,
+    // comma separated, like `BACKEND_PRIVATE_KEY`
+    pub(crate) backend_private_key: Option<String>,
+    pub(crate) proxy_creation_code: Option<String>,
+    pub(crate) refund_gas_tokens: Option<String>,
+    pub(crate) gas_token_prices: Option<String>,
+    pub(crate) multi_send_address: Option<String>,
+    pub(crate) multicall_address: Option<String>,
+}
+
+/// Everything wrong with the configuration, so it can be fixed in one go.
+pub(crate) struct ConfigErrors(Vec<String>);
+
+impl From<String> for ConfigErrors {
+    fn from(error: String) -> Self {
+        Self(vec![error])
+    }
+}
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} configuration error(s):", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the TOML config file, each overridden by the environment variable of the same
+/// name. File keys are the variable names in lower case, like `rpc_url`.
+struct ConfigSource {
+    file: toml::value::Table,
+    env: HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl ConfigSource {
+    fn new(path: Option<&str>) -> Self {
+        // variables that are not unicode are left unset, like `env::var` does
+        let env = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(file) => Self::parse(path, &file, env),
+                Err(e) => Self {
+                    file: toml::value::Table::new(),
+                    env,
+                    errors: vec![format!("Config file {path} is not readable: {e}")],
+                },
+            },
+            None => Self {
+                file: toml::value::Table::new(),
+                env,
+                errors: Vec::new(),
+            },
+        }
+    }
+
+    /// Settings of the config file `path` holding `file`, overridden by `env`.
+    fn parse(path: &str, file: &str, env: HashMap<String, String>) -> Self {
+        let mut errors = Vec::new();
+        let file = file.parse::<Value>()
+            .map_err(|e| format!("Config file {path} is not valid TOML: {e}"))
+            .and_then(|file| match file {
+                Value::Table(table) => Ok(table),
+                _ => Err(format!("Config file {path} must be a table")),
+            })
+            .unwrap_or_else(|e| {
+                errors.push(e);
+                toml::value::Table::new()
+            });
+        Self { file, env, errors }
+    }
+
+    fn var(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.env.get(name) {
+            return Some(value.clone());
+        }
+        let key = name.to_lowercase();
+        match self.file.get(&key)? {
+            Value::String(value) => Some(value.clone()),
+            Value::Integer(value) => Some(value.to_string()),
+            Value::Boolean(value) => Some(value.to_string()),
+            // several keys can be listed instead of comma separated
+            Value::Array(values) if values.iter().all(Value::is_str) => {
+                Some(values.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(","))
+            }
+            _ => {
+                self.errors.push(format!("{key} must be a string or a number"));
+                None
+            }
+        }
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        self.var(name).unwrap_or_else(|| {
+            self.errors.push(format!("{name} must be set"));
+            String::new()
+        })
+    }
+
+    fn or(&mut self, name: &str, default: &str) -> String {
+        self.var(name).unwrap_or_else(|| default.to_string())
+    }
+
+    fn number<T: FromStr>(&mut self, name: &str, default: T, unit: &str) -> T {
+        match self.var(name) {
+            Some(value) => value.trim().parse::<T>().unwrap_or_else(|_| {
+                self.errors.push(format!("{name} must be {unit}, not {value}"));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    fn flag(&mut self, name: &str, default: bool) -> bool {
+        match self.var(name) {
+            Some(value) => match value.trim() {
+                "true" => true,
+                "false" => false,
+                _ => {
+                    self.errors.push(format!("{name} must be true or false, not {value}"));
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    /// Entries of the JSON file named by `file_var`, or else of the `key` tables of the config file.
+    fn entries<T: DeserializeOwned>(&mut self, file_var: &str, key: &str) -> Option<Vec<T>> {
+        let entries = match self.var(file_var) {
+            Some(path) => fs::read_to_string(&path)
+                .map_err(|e| format!("{file_var} {path} is not readable: {e}"))
+                .and_then(|entries| serde_json::from_str::<Vec<T>>(&entries)
+                    .map_err(|e| format!("{file_var} {path} must be a JSON array of {key}: {e}"))),
+            None => self.file.get(key)?.clone()
+                .try_into::<Vec<T>>()
+                .map_err(|e| format!("[[{key}]] of the config file: {e}")),
+        };
+        entries.map_err(|e| self.errors.push(e)).ok()
+    }
+}
 
 #[derive(Clone)]
 pub(crate) struct SafeConfig {
+    pub(crate) address: String,
+    pub(crate) port: u16,
     pub(crate) rpc_url: String,
-    pub(crate) backend_private_key: String,
+    // checked against the RPC when set
+    pub(crate) chain_id: Option<u64>,
+    pub(crate) backend_private_keys: Vec<String>,
+    pub(crate) relayer_strategy: String,
+    pub(crate) relayer_min_balance: String,
+    pub(crate) balance_poll_interval: u64,
     pub(crate) fallback_addr: String,
     pub(crate) master_copy_addr: String,
     pub(crate) proxy_factory_addr: String,
     pub(crate) salt_nonce: String,
+    pub(crate) proxy_creation_code: Option<String>,
+    pub(crate) contract_check: String,
+    pub(crate) known_code_hashes: String,
+    pub(crate) multi_send_addr: Option<String>,
+    pub(crate) multi_send_call_only: bool,
+    pub(crate) multicall_addr: Option<String>,
+    pub(crate) relay_poll_interval: u64,
+    pub(crate) relay_confirmations: u64,
+    pub(crate) gas_bump_percent: u64,
+    pub(crate) gas_bump_max_fee: String,
+    pub(crate) gas_bump_interval: u64,
+    pub(crate) refund_gas_tokens: String,
+    pub(crate) gas_token_prices: String,
+    pub(crate) quote_ttl: u64,
+    pub(crate) sponsor_deploys_per_address: u32,
+    pub(crate) sponsor_deploys_per_ip: u32,
+    pub(crate) sponsor_deploys_per_day: u32,
+    pub(crate) siwe_domain: String,
+    pub(crate) auth_nonce_ttl: u64,
+    pub(crate) tenants: Option<Vec<TenantConfig>>,
+    pub(crate) rate_limit_read: u32,
+    pub(crate) rate_limit_deploy: u32,
+    pub(crate) rate_limit_exec: u32,
+    pub(crate) chains: Vec<ChainConfig>,
 }
 
 impl SafeConfig {
-    pub(crate) fn new() -> Self {
-        let rpc_url = env::var("RPC_URL").expect("RPC_URL must be set");
-        let backend_private_key = env::var("BACKEND_PRIVATE_KEY")
-            .expect("BACKEND_PRIVATE_KEY must be set");
-        let fallback_addr = env::var("FALLBACK_ADDRESS")
-            .expect("FALLBACK_ADDRESS must be set");
-        let master_copy_addr = env::var("MASTER_COPY_CONTRACT_ADDRESS")
-            .expect("MASTER_COPY_CONTRACT_ADDRESS must be set");
-        let proxy_factory_addr = env::var("PROXY_FACTORY_CONTRACT_ADDRESS")
-            .expect("PROXY_FACTORY_CONTRACT_ADDRESS must be set");
-        let salt_nonce = env::var("SALT_NONCE")
-            .expect("SALT_NONCE must be set");
+    /// Reads the config file at `path` and the environment, then checks the result
+    /// down to the RPCs answering for the expected chains.
+    pub(crate) async fn load(path: Option<&str>) -> Result<Self, ConfigErrors> {
+        let mut source = ConfigSource::new(path);
+        let safe_config = Self::read(&mut source);
+        let mut errors = source.errors;
+        safe_config.validate(&mut errors);
+        safe_config.check_chains(&mut errors).await;
+        if errors.is_empty() {
+            Ok(safe_config)
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    fn read(source: &mut ConfigSource) -> Self {
+        let address = source.required("ADDRESS");
+        let port = match source.var("PORT") {
+            Some(port) => port.trim().parse::<u16>().unwrap_or_else(|_| {
+                source.errors.push(format!("PORT must be a port number, not {port}"));
+                0
+            }),
+            None => {
+                source.errors.push("PORT must be set".to_string());
+                0
+            }
+        };
+        let rpc_url = source.required("RPC_URL");
+        let chain_id = source.var("CHAIN_ID").and_then(|chain_id| match chain_id.trim().parse::<u64>() {
+            Ok(chain_id) => Some(chain_id),
+            Err(_) => {
+                source.errors.push(format!("CHAIN_ID must be a number, not {chain_id}"));
+                None
+            }
+        });
+        let backend_private_keys = split_keys(&source.required("BACKEND_PRIVATE_KEY"));
+        let relayer_strategy = source.or("RELAYER_STRATEGY", "round-robin");
+        let relayer_min_balance = source.or("RELAYER_MIN_BALANCE", "0");
+        let balance_poll_interval = source.number("BALANCE_POLL_INTERVAL", 30, "a number of seconds");
+        let chains = source.entries::<ChainConfig>("CHAINS_FILE", "chains").unwrap_or_default();
+        let fallback_addr = source.required("FALLBACK_ADDRESS");
+        let master_copy_addr = source.required("MASTER_COPY_CONTRACT_ADDRESS");
+        let proxy_factory_addr = source.required("PROXY_FACTORY_CONTRACT_ADDRESS");
+        let salt_nonce = source.required("SALT_NONCE");
+        let proxy_creation_code = source.var("PROXY_CREATION_CODE");
+        let contract_check = source.or("CONTRACT_CHECK", "strict");
+        let known_code_hashes = source.or("KNOWN_CODE_HASHES", "");
+        let multi_send_addr = source.var("MULTI_SEND_ADDRESS");
+        let multi_send_call_only = source.flag("MULTI_SEND_CALL_ONLY", true);
+        let multicall_addr = source.var("MULTICALL_ADDRESS");
+        let relay_poll_interval = source.number("RELAY_POLL_INTERVAL", 5, "a number of seconds");
+        let relay_confirmations = source.number("RELAY_CONFIRMATIONS", 12, "a number");
+        let gas_bump_percent = source.number("GAS_BUMP_PERCENT", 20, "a number");
+        let gas_bump_max_fee = source.or("GAS_BUMP_MAX_FEE", "500000000000");
+        let gas_bump_interval = source.number("GAS_BUMP_INTERVAL", 60, "a number of seconds");
+        // ether at no less than the network gas price unless configured otherwise
+        let refund_gas_tokens = source.or("REFUND_GAS_TOKENS", "0x0000000000000000000000000000000000000000:1");
+        let gas_token_prices = source.or("GAS_TOKEN_PRICES", "");
+        let quote_ttl = source.number("QUOTE_TTL", 120, "a number of seconds");
+        let sponsor_deploys_per_address = source.number("SPONSOR_DEPLOYS_PER_ADDRESS", 1, "a number");
+        let sponsor_deploys_per_ip = source.number("SPONSOR_DEPLOYS_PER_IP", 10, "a number");
+        let sponsor_deploys_per_day = source.number("SPONSOR_DEPLOYS_PER_DAY", 1000, "a number");
+        let siwe_domain = source.required("SIWE_DOMAIN");
+        let auth_nonce_ttl = source.number("AUTH_NONCE_TTL", 300, "a number of seconds");
+        let tenants = source.entries::<TenantConfig>("TENANTS_FILE", "tenants");
+        let rate_limit_read = source.number("RATE_LIMIT_READ", 120, "a number of requests per minute");
+        let rate_limit_deploy = source.number("RATE_LIMIT_DEPLOY", 5, "a number of requests per minute");
+        let rate_limit_exec = source.number("RATE_LIMIT_EXEC", 30, "a number of requests per minute");
 
         Self {
+            address,
+            port,
             rpc_url,
-            backend_private_key,
+            chain_id,
+            backend_private_keys,
+            relayer_strategy,
+            relayer_min_balance,
+            balance_poll_interval,
             fallback_addr,
             master_copy_addr,
             proxy_factory_addr,
             salt_nonce,
+            proxy_creation_code,
+            contract_check,
+            known_code_hashes,
+            multi_send_addr,
+            multi_send_call_only,
+            multicall_addr,
+            relay_poll_interval,
+            relay_confirmations,
+            gas_bump_percent,
+            gas_bump_max_fee,
+            gas_bump_interval,
+            refund_gas_tokens,
+            gas_token_prices,
+            quote_ttl,
+            sponsor_deploys_per_address,
+            sponsor_deploys_per_ip,
+            sponsor_deploys_per_day,
+            siwe_domain,
+            auth_nonce_ttl,
+            tenants,
+            rate_limit_read,
+            rate_limit_deploy,
+            rate_limit_exec,
+            chains,
+        }
+    }
+
+    /// Configuration of an additional chain served next to the default one.
+    pub(crate) fn for_chain(&self, chain: &ChainConfig) -> Self {
+        let backend_private_keys = match &chain.backend_private_key {
+            Some(keys) => split_keys(keys),
+            None => self.backend_private_keys.clone(),
+        };
+        Self {
+            rpc_url: chain.rpc_url.clone(),
+            chain_id: Some(chain.chain_id),
+            backend_private_keys,
+            fallback_addr: chain.fallback_address.clone(),
+            master_copy_addr: chain.master_copy_address.clone(),
+            proxy_factory_addr: chain.proxy_factory_address.clone(),
+            proxy_creation_code: chain.proxy_creation_code.clone(),
+            multi_send_addr: chain.multi_send_address.clone().or_else(|| self.multi_send_addr.clone()),
+            multicall_addr: chain.multicall_address.clone().or_else(|| self.multicall_addr.clone()),
+            refund_gas_tokens: chain.refund_gas_tokens.clone().unwrap_or_else(|| self.refund_gas_tokens.clone()),
+            gas_token_prices: chain.gas_token_prices.clone().unwrap_or_else(|| self.gas_token_prices.clone()),
+            chains: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// The default chain followed by the additional ones, each named for error messages.
+    fn all_chains(&self) -> Vec<(String, SafeConfig)> {
+        let mut chains = vec![("default chain".to_string(), self.clone())];
+        chains.extend(self.chains.iter().map(|chain| (format!("chain {}", chain.chain_id), self.for_chain(chain))));
+        chains
+    }
+
+    /// Checks every value parses the way the services will parse it.
+    fn validate(&self, errors: &mut Vec<String>) {
+        if let Err(e) = self.relayer_strategy.parse::<RelayerStrategy>() {
+            errors.push(format!("RELAYER_STRATEGY: {e}"));
+        }
+        if let Err(e) = self.contract_check.parse::<ContractCheck>() {
+            errors.push(format!("CONTRACT_CHECK: {e}"));
+        }
+        if let Err(e) = parse_code_hashes(&self.known_code_hashes) {
+            errors.push(format!("KNOWN_CODE_HASHES: {e}"));
+        }
+        check_decimal(errors, "RELAYER_MIN_BALANCE", &self.relayer_min_balance);
+        check_decimal(errors, "GAS_BUMP_MAX_FEE", &self.gas_bump_max_fee);
+        if self.gas_bump_percent < MIN_BUMP_PERCENT {
+            errors.push(format!("GAS_BUMP_PERCENT must be at least {MIN_BUMP_PERCENT}, nodes refuse smaller replacements"));
         }
+        check_salt_nonce(errors, "SALT_NONCE", &self.salt_nonce);
+
+        let mut api_keys = HashSet::new();
+        for tenant in self.tenants.iter().flatten() {
+            if let Some(fallback_address) = &tenant.fallback_address {
+                check_address(errors, &format!("fallbackAddress of tenant {}", tenant.name), fallback_address);
+            }
+            if let Some(salt_nonce) = &tenant.salt_nonce {
+                check_salt_nonce(errors, &format!("saltNonce of tenant {}", tenant.name), salt_nonce);
+            }
+            for api_key in &tenant.api_keys {
+                if !api_keys.insert(api_key) {
+                    errors.push(format!("API key of tenant {} is used by another tenant", tenant.name));
+                }
+            }
+        }
+
+        let mut chain_ids = HashSet::new();
+        for chain in &self.chains {
+            if !chain_ids.insert(chain.chain_id) {
+                errors.push(format!("Chain {} is configured twice", chain.chain_id));
+            }
+        }
+        for (name, chain) in self.all_chains() {
+            check_address(errors, &format!("FALLBACK_ADDRESS of {name}"), &chain.fallback_addr);
+            check_address(errors, &format!("MASTER_COPY_CONTRACT_ADDRESS of {name}"), &chain.master_copy_addr);
+            check_address(errors, &format!("PROXY_FACTORY_CONTRACT_ADDRESS of {name}"), &chain.proxy_factory_addr);
+            if let Some(multi_send_addr) = &chain.multi_send_addr {
+                check_address(errors, &format!("MULTI_SEND_ADDRESS of {name}"), multi_send_addr);
+            }
+            if let Some(multicall_addr) = &chain.multicall_addr {
+                check_address(errors, &format!("MULTICALL_ADDRESS of {name}"), multicall_addr);
+            }
+            if chain.backend_private_keys.is_empty() && !chain.rpc_url.is_empty() {
+                errors.push(format!("BACKEND_PRIVATE_KEY of {name} must hold at least one key"));
+            }
+            for (i, backend_private_key) in chain.backend_private_keys.iter().enumerate() {
+                // never echo the key itself
+                let valid = hex::decode(backend_private_key)
+                    .map(|key| SecretKey::from_be_bytes(&key).is_ok())
+                    .unwrap_or(false);
+                if !valid {
+                    errors.push(format!("BACKEND_PRIVATE_KEY #{} of {name} is not a hex private key", i + 1));
+                }
+            }
+            if let Some(code) = &chain.proxy_creation_code {
+                if hex::decode(code.trim_start_matches("0x")).is_err() {
+                    errors.push(format!("PROXY_CREATION_CODE of {name} is not hex"));
+                }
+            }
+            if let Err(e) = chain.refund_gas_tokens.parse::<RefundPolicy>() {
+                errors.push(format!("REFUND_GAS_TOKENS of {name}: {e}"));
+            }
+            if let Err(e) = chain.gas_token_prices.parse::<StaticPriceTable>() {
+                errors.push(format!("GAS_TOKEN_PRICES of {name}: {e}"));
+            }
+        }
+    }
+
+    /// Checks every RPC answers, for the chain it is configured for,
+    /// and that the chain's contracts are Safe contracts.
+    async fn check_chains(&self, errors: &mut Vec<String>) {
+        // bad values are reported by `validate`
+        let contract_check = self.contract_check.parse::<ContractCheck>().unwrap_or(ContractCheck::Off);
+        let known_code_hashes = parse_code_hashes(&self.known_code_hashes).unwrap_or_default();
+        let mut served = HashSet::new();
+        for (name, chain) in self.all_chains() {
+            if chain.rpc_url.is_empty() {
+                continue;
+            }
+            let provider = match Provider::<Http>::try_from(chain.rpc_url.as_str()) {
+                Ok(provider) => provider,
+                Err(e) => {
+                    errors.push(format!("RPC_URL of {name} is not a URL: {e}"));
+                    continue;
+                }
+            };
+            match provider.get_chainid().await {
+                Ok(chain_id) => {
+                    if matches!(chain.chain_id, Some(expected) if U256::from(expected) != chain_id) {
+                        errors.push(format!("RPC of {name} serves chain {chain_id}"));
+                    } else if !served.insert(chain_id) {
+                        errors.push(format!("RPC of {name} serves chain {chain_id}, which is already configured"));
+                    }
+                }
+                Err(e) => {
+                    errors.push(format!("RPC of {name} is unreachable: {e}"));
+                    continue;
+                }
+            }
+
+            if contract_check == ContractCheck::Off {
+                continue;
+            }
+            let contracts: Vec<(ContractKind, Address)> = [
+                (ContractKind::MasterCopy, Some(&chain.master_copy_addr)),
+                (ContractKind::ProxyFactory, Some(&chain.proxy_factory_addr)),
+                (ContractKind::FallbackHandler, Some(&chain.fallback_addr)),
+                (ContractKind::MultiSend, chain.multi_send_addr.as_ref()),
+                (ContractKind::Multicall, chain.multicall_addr.as_ref()),
+            ].into_iter()
+                .filter_map(|(kind, address)| address?.parse::<Address>().ok().map(|address| (kind, address)))
+                .collect();
+            for problem in verify_contracts(&provider, &contracts, &known_code_hashes).await {
+                match contract_check {
+                    ContractCheck::Strict => errors.push(format!("{problem} on {name}")),
+                    _ => warn!("{problem} on {name}"),
+                }
+            }
+        }
+    }
+}
+
+fn split_keys(keys: &str) -> Vec<String> {
+    keys.split(',')
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect()
+}
+
+fn check_address(errors: &mut Vec<String>, name: &str, value: &str) {
+    // missing values are reported when read
+    if value.is_empty() {
+        return;
+    }
+    match value.parse::<Address>() {
+        Ok(address) => {
+            // single case addresses carry no checksum
+            let digits = value.trim_start_matches("0x");
+            let checksummed = to_checksum(&address, None);
+            if digits != digits.to_lowercase() && digits != digits.to_uppercase() && checksummed[2..] != *digits {
+                errors.push(format!("{name} {value} has a bad checksum, expected {checksummed}"));
+            }
+        }
+        Err(e) => errors.push(format!("{name} {value} is not an address: {e}")),
+    }
+}
+
+fn check_decimal(errors: &mut Vec<String>, name: &str, value: &str) {
+    if U256::from_dec_str(value).is_err() {
+        errors.push(format!("{name} must be a decimal amount of wei, not {value}"));
+    }
+}
+
+fn check_salt_nonce(errors: &mut Vec<String>, name: &str, value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    match hex::decode(value) {
+        Ok(salt_nonce) if salt_nonce.len() <= 32 => {}
+        _ => errors.push(format!("{name} must be at most 32 bytes of hex, not {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    const CONFIG: &str = r#"
+        address = "0.0.0.0"
+        port = 8080
+        rpc_url = "http://localhost:8545"
+        backend_private_key = ["0101010101010101010101010101010101010101010101010101010101010101"]
+        fallback_address = "0xf48f2b2d2a534e402487b3ee7c18c33aec0fe5e4"
+        master_copy_contract_address = "0xd9db270c1b5e3bd161e8c8503c55ceabee709552"
+        proxy_factory_contract_address = "0xa6b71e26c5e0845f74c812102ca7114b6a896ab2"
+        salt_nonce = "00"
+        siwe_domain = "relay.example.com"
+    "#;
+
+    type Vars<'a> = &'a [(&'a str, &'a str)];
+
+    fn env(vars: Vars) -> HashMap<String, String> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    /// The configuration read from `file` and `vars`, with every error reading and validating it.
+    fn config(file: &str, vars: Vars) -> (SafeConfig, Vec<String>) {
+        let mut source = ConfigSource::parse("config.toml", file, env(vars));
+        let safe_config = SafeConfig::read(&mut source);
+        let mut errors = source.errors;
+        safe_config.validate(&mut errors);
+        (safe_config, errors)
+    }
+
+    #[test]
+    fn settings_come_from_the_file_unless_the_environment_has_them() {
+        let cases: [(&str, Vars, &str, Option<&str>); 8] = [
+            (r#"rpc_url = "http://file""#, &[], "RPC_URL", Some("http://file")),
+            (r#"rpc_url = "http://file""#, &[("RPC_URL", "http://env")], "RPC_URL", Some("http://env")),
+            ("", &[("RPC_URL", "http://env")], "RPC_URL", Some("http://env")),
+            (r#"RPC_URL = "http://file""#, &[], "RPC_URL", None),
+            ("", &[("rpc_url", "http://env")], "RPC_URL", None),
+            ("port = 8080", &[], "PORT", Some("8080")),
+            ("multi_send_call_only = false", &[], "MULTI_SEND_CALL_ONLY", Some("false")),
+            (r#"backend_private_key = ["01", "02"]"#, &[], "BACKEND_PRIVATE_KEY", Some("01,02")),
+        ];
+        for (file, vars, name, value) in cases {
+            let mut source = ConfigSource::parse("config.toml", file, env(vars));
+            assert_eq!(source.var(name).as_deref(), value, "{file} {vars:?}");
+            assert!(source.errors.is_empty(), "{file}: {:?}", source.errors);
+        }
+    }
+
+    #[test]
+    fn bad_settings_are_reported() {
+        let cases = [
+            ("port = [8080, 8081]", "port must be a string or a number"),
+            ("port = 8080.5", "port must be a string or a number"),
+            ("port = { number = 8080 }", "port must be a string or a number"),
+            ("port = ", "Config file config.toml is not valid TOML"),
+        ];
+        for (file, error) in cases {
+            let mut source = ConfigSource::parse("config.toml", file, HashMap::new());
+            assert_eq!(source.var("PORT"), None, "{file}");
+            assert_eq!(source.errors.len(), 1, "{file}: {:?}", source.errors);
+            assert!(source.errors[0].starts_with(error), "{file}: {}", source.errors[0]);
+        }
+        let source = ConfigSource::new(Some("/nonexistent/config.toml"));
+        assert!(source.errors[0].starts_with("Config file /nonexistent/config.toml is not readable"), "{:?}", source.errors);
+    }
+
+    #[test]
+    fn valid_configs_have_no_errors() {
+        let (safe_config, errors) = config(CONFIG, &[]);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(safe_config.port, 8080);
+        assert_eq!(safe_config.backend_private_keys, [KEY]);
+        assert_eq!(safe_config.relayer_strategy, "round-robin");
+        assert_eq!(safe_config.contract_check, "strict");
+        assert!(safe_config.multi_send_call_only);
+        assert!(safe_config.tenants.is_none());
+    }
+
+    #[test]
+    fn missing_settings_are_reported() {
+        let (_, errors) = config("", &[]);
+        let missing = ["ADDRESS", "PORT", "RPC_URL", "BACKEND_PRIVATE_KEY", "FALLBACK_ADDRESS",
+            "MASTER_COPY_CONTRACT_ADDRESS", "PROXY_FACTORY_CONTRACT_ADDRESS", "SALT_NONCE", "SIWE_DOMAIN"];
+        let expected: Vec<String> = missing.iter().map(|name| format!("{name} must be set")).collect();
+        assert_eq!(errors, expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn every_error_is_reported() {
+        let file = format!(r#"
+            {CONFIG}
+            relayer_strategy = "random"
+            relayer_min_balance = "1 ether"
+            contract_check = "maybe"
+            multi_send_call_only = "yes"
+            gas_bump_percent = 5
+            quote_ttl = "soon"
+            fallback_address = "0xF48f2b2d2a534e402487b3ee7c18c33aec0fe5e4"
+            refund_gas_tokens = "0x12:1"
+
+            [[tenants]]
+            name = "wallet"
+            apiKeys = ["key"]
+            saltNonce = "xyz"
+
+            [[tenants]]
+            name = "game"
+            apiKeys = ["key"]
+            fallbackAddress = "0x12"
+
+            [[chains]]
+            chainId = 137
+            rpcUrl = "http://polygon"
+            proxyFactoryAddress = "0xa6b71e26c5e0845f74c812102ca7114b6a896ab2"
+            masterCopyAddress = "0xd9db270c1b5e3bd161e8c8503c55ceabee709552"
+            fallbackAddress = "0xf48f2b2d2a534e402487b3ee7c18c33aec0fe5e4"
+            backendPrivateKey = "xyz"
+
+            [[chains]]
+            chainId = 137
+            rpcUrl = "http://polygon-2"
+            proxyFactoryAddress = "0xa6b71e26c5e0845f74c812102ca7114b6a896ab2"
+            masterCopyAddress = "not an address"
+            fallbackAddress = "0xf48f2b2d2a534e402487b3ee7c18c33aec0fe5e4"
+        "#).replace(r#"fallback_address = "0xf48f2b2d2a534e402487b3ee7c18c33aec0fe5e4""#, "");
+        let (_, errors) = config(&file, &[("PORT", "http")]);
+        let expected = [
+            "PORT must be a port number, not http",
+            "MULTI_SEND_CALL_ONLY must be true or false, not yes",
+            "QUOTE_TTL must be a number of seconds, not soon",
+            "RELAYER_STRATEGY: Unknown relayer strategy random",
+            "CONTRACT_CHECK: ",
+            "RELAYER_MIN_BALANCE must be a decimal amount of wei, not 1 ether",
+            "GAS_BUMP_PERCENT must be at least",
+            "saltNonce of tenant wallet must be at most 32 bytes of hex, not xyz",
+            "fallbackAddress of tenant game 0x12 is not an address",
+            "API key of tenant game is used by another tenant",
+            "Chain 137 is configured twice",
+            "FALLBACK_ADDRESS of default chain 0xF48f2b2d2a534e402487b3ee7c18c33aec0fe5e4 has a bad checksum, expected 0xf48f2B2d2a534e402487b3ee7C18c33Aec0Fe5e4",
+            "REFUND_GAS_TOKENS of default chain: ",
+            "BACKEND_PRIVATE_KEY #1 of chain 137 is not a hex private key",
+            "MASTER_COPY_CONTRACT_ADDRESS of chain 137 not an address is not an address",
+        ];
+        for error in expected {
+            assert!(errors.iter().any(|e| e.starts_with(error)), "{error} is missing from {errors:#?}");
+        }
+        // both chains 137 inherit the bad refund tokens
+        let inherited = errors.iter().filter(|e| e.starts_with("REFUND_GAS_TOKENS of chain 137: ")).count();
+        assert_eq!(inherited, 2, "{errors:#?}");
+        assert_eq!(errors.len(), expected.len() + inherited, "{errors:#?}");
+    }
+
+    #[test]
+    fn chains_inherit_what_they_do_not_set() {
+        let file = format!(r#"
+            {CONFIG}
+            multicall_address = "0xca11bde05977b3631167028862be2a173976ca11"
+            refund_gas_tokens = "0x0000000000000000000000000000000000000000:2"
+
+            [[chains]]
+            chainId = 137
+            rpcUrl = "http://polygon"
+            proxyFactoryAddress = "0x0000000000000000000000000000000000000001"
+            masterCopyAddress = "0x0000000000000000000000000000000000000002"
+            fallbackAddress = "0x0000000000000000000000000000000000000003"
+
+            [[chains]]
+            chainId = 100
+            rpcUrl = "http://gnosis"
+            proxyFactoryAddress = "0x0000000000000000000000000000000000000001"
+            masterCopyAddress = "0x0000000000000000000000000000000000000002"
+            fallbackAddress = "0x0000000000000000000000000000000000000003"
+            backendPrivateKey = "0202020202020202020202020202020202020202020202020202020202020202, 0303030303030303030303030303030303030303030303030303030303030303"
+            multicallAddress = "0x0000000000000000000000000000000000000004"
+            refundGasTokens = "0x0000000000000000000000000000000000000000:3"
+            multiSendAddress = "0x0000000000000000000000000000000000000005"
+        "#);
+        let (safe_config, errors) = config(&file, &[]);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(safe_config.chains.len(), 2);
+
+        let polygon = safe_config.for_chain(&safe_config.chains[0]);
+        assert_eq!(polygon.chain_id, Some(137));
+        assert_eq!(polygon.rpc_url, "http://polygon");
+        assert_eq!(polygon.master_copy_addr, "0x0000000000000000000000000000000000000002");
+        assert_eq!(polygon.backend_private_keys, [KEY]);
+        assert_eq!(polygon.multicall_addr.as_deref(), Some("0xca11bde05977b3631167028862be2a173976ca11"));
+        assert_eq!(polygon.multi_send_addr, None);
+        assert_eq!(polygon.refund_gas_tokens, "0x0000000000000000000000000000000000000000:2");
+        assert_eq!(polygon.siwe_domain, "relay.example.com");
+        assert!(polygon.chains.is_empty());
+
+        let gnosis = safe_config.for_chain(&safe_config.chains[1]);
+        assert_eq!(gnosis.backend_private_keys, ["02".repeat(32), "03".repeat(32)]);
+        assert_eq!(gnosis.multicall_addr.as_deref(), Some("0x0000000000000000000000000000000000000004"));
+        assert_eq!(gnosis.multi_send_addr.as_deref(), Some("0x0000000000000000000000000000000000000005"));
+        assert_eq!(gnosis.refund_gas_tokens, "0x0000000000000000000000000000000000000000:3");
+    }
+}
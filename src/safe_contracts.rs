@@ -0,0 +1,219 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Address, H256};
+use ethers::utils::keccak256;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::safe_service::MasterCopy;
+
+/// What to do when a configured contract is not a known Safe deployment.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContractCheck {
+    // refuse to start
+    Strict,
+    // log and carry on
+    Warn,
+    Off,
+}
+
+impl FromStr for ContractCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ContractCheck::Strict),
+            "warn" => Ok(ContractCheck::Warn),
+            "off" => Ok(ContractCheck::Off),
+            _ => Err(format!("Unknown contract check {s}, expected strict, warn or off")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ContractKind {
+    MasterCopy,
+    ProxyFactory,
+    FallbackHandler,
+    MultiSend,
+    MultiSendCallOnly,
+    Multicall,
+}
+
+impl ContractKind {
+    /// Whether an address configured as this kind may hold a deployment of `kind`,
+    /// MultiSend addresses may hold the call only variant.
+    fn accepts(self, kind: ContractKind) -> bool {
+        self == kind || (self, kind) == (ContractKind::MultiSend, ContractKind::MultiSendCallOnly)
+    }
+}
+
+impl Display for ContractKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContractKind::MasterCopy => write!(f, "master copy"),
+            ContractKind::ProxyFactory => write!(f, "proxy factory"),
+            ContractKind::FallbackHandler => write!(f, "fallback handler"),
+            ContractKind::MultiSend => write!(f, "MultiSend"),
+            ContractKind::MultiSendCallOnly => write!(f, "MultiSendCallOnly"),
+            ContractKind::Multicall => write!(f, "multicall"),
+        }
+    }
+}
+
+// deployed through the same deterministic deployer, so at the same address with the same code on every chain
+const CANONICAL_DEPLOYMENTS: &[(ContractKind, &str, &str)] = &[
+    (ContractKind::MasterCopy, "1.1.1", "0x34CfAC646f301356fAa8B21e94227e3583Fe3F5F"),
+    (ContractKind::MasterCopy, "1.3.0", "0xd9Db270c1B5E3Bd161E8c8503c55cEABeE709552"),
+    (ContractKind::MasterCopy, "1.3.0", "0x3E5c63644E683549055b9Be8653de26E0B4CD36E"),
+    (ContractKind::MasterCopy, "1.4.1", "0x41675C099F32341bf84BFc5382aF534df5C7461a"),
+    (ContractKind::MasterCopy, "1.4.1", "0x29fcB43b46531BcA003ddC8FCB67FFE91900C762"),
+    (ContractKind::ProxyFactory, "1.1.1", "0x76E2cFc1F5Fa8F6a5b3fC4c8F4788F0116861F9B"),
+    (ContractKind::ProxyFactory, "1.3.0", "0xa6B71E26C5e0845f74c812102Ca7114b6a896AB2"),
+    (ContractKind::ProxyFactory, "1.4.1", "0x4e1DCf7AD4e460CfD30791CCC4F9c8a4f820ec67"),
+    (ContractKind::FallbackHandler, "1.1.1", "0xd5D82B6aDDc9027B22dCA772Aa68D5d74cdBdF44"),
+    (ContractKind::FallbackHandler, "1.3.0", "0xf48f2B2d2a534e402487b3ee7C18c33Aec0Fe5e4"),
+    (ContractKind::FallbackHandler, "1.4.1", "0xfd0732Dc9E303f09fCEf3a7388Ad10A83459Ec99"),
+    (ContractKind::MultiSend, "1.1.1", "0x8D29bE29923b68abfDD21e541b9374737B49cdAD"),
+    (ContractKind::MultiSend, "1.3.0", "0xA238CBeb142c10Ef7Ad8442C6D1f9E89e07e7761"),
+    (ContractKind::MultiSend, "1.4.1", "0x38869bf66a61cF6bDB996A6aE40D5853Fd43B526"),
+    (ContractKind::MultiSendCallOnly, "1.3.0", "0x40A2aCCbd92BCA938b02010E17A5b8929b49130D"),
+    (ContractKind::MultiSendCallOnly, "1.4.1", "0x9641d764fc13c8B624c04430C7356C1C7C8102e2"),
+    (ContractKind::Multicall, "3", "0xcA11bde05977b3631167028862bE2a173976CA11"),
+];
+
+/// Runtime code hash of a canonical deployment, as printed by `--print-code-hashes`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CodeHash {
+    kind: ContractKind,
+    version: String,
+    code_hash: H256,
+}
+
+// generated from a chain holding the canonical deployments, e.g. the hardhat mainnet fork
+const CANONICAL_CODE_HASHES: &str = include_str!("../abi/canonical_code_hashes.json");
+
+fn canonical_code_hashes() -> Vec<CodeHash> {
+    serde_json::from_str(CANONICAL_CODE_HASHES).expect("abi/canonical_code_hashes.json must be a list of code hashes")
+}
+
+/// Hashes the code of every canonical deployment on the chain behind `provider`, skipping missing ones.
+pub(crate) async fn print_code_hashes(provider: &Provider<Http>) -> Result<String, String> {
+    let mut code_hashes = Vec::new();
+    for (kind, version, address) in CANONICAL_DEPLOYMENTS {
+        let code = provider.get_code(address.parse::<Address>().unwrap(), None).await
+            .map_err(|e| format!("{kind} {address} code is not readable: {e}"))?;
+        if code.is_empty() {
+            continue;
+        }
+        let code_hash = CodeHash { kind: *kind, version: version.to_string(), code_hash: H256::from(keccak256(&code)) };
+        // L2 variants share the kind and version of their deployment
+        if !code_hashes.iter().any(|known: &CodeHash| known.code_hash == code_hash.code_hash) {
+            code_hashes.push(code_hash);
+        }
+    }
+    serde_json::to_string_pretty(&code_hashes).map_err(|e| e.to_string())
+}
+
+/// Parses comma separated keccak256 hashes of runtime code.
+pub(crate) fn parse_code_hashes(s: &str) -> Result<Vec<H256>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|hash| !hash.is_empty())
+        .map(|hash| hash.parse::<H256>().map_err(|e| format!("Code hash {hash}: {e}")))
+        .collect()
+}
+
+/// Checks every contract has code, and code that is either in `known_code_hashes` or the embedded
+/// code hash of a canonical deployment of its kind. Returns what is wrong, empty when nothing is.
+pub(crate) async fn verify_contracts(provider: &Provider<Http>,
+                                     contracts: &[(ContractKind, Address)],
+                                     known_code_hashes: &[H256]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (kind, address) in contracts {
+        let code_hash = match provider.get_code(*address, None).await {
+            Ok(code) if code.is_empty() => {
+                problems.push(format!("{kind} {address:?} has no code"));
+                continue;
+            }
+            Ok(code) => H256::from(keccak256(&code)),
+            Err(e) => {
+                problems.push(format!("{kind} {address:?} code is not readable: {e}"));
+                continue;
+            }
+        };
+
+        let version = if known_code_hashes.contains(&code_hash) {
+            None
+        } else {
+            match canonical_deployment(*kind, code_hash) {
+                Some((_, version)) => Some(version),
+                None => {
+                    problems.push(format!("{kind} {address:?} is no known deployment, its code hash is {code_hash:?}"));
+                    continue;
+                }
+            }
+        };
+
+        if *kind == ContractKind::MasterCopy {
+            let master_copy = MasterCopy::new(*address, Arc::new(provider.clone()));
+            match master_copy.version().call().await {
+                Ok(actual) => {
+                    debug!("Master copy {address:?} is Safe {actual}");
+                    if let Some(version) = version.filter(|version| *version != actual) {
+                        problems.push(format!("{kind} {address:?} reports VERSION {actual} but has the code of Safe {version}"));
+                    }
+                }
+                Err(e) => problems.push(format!("{kind} {address:?} does not answer VERSION(): {e}")),
+            }
+        }
+    }
+    problems
+}
+
+/// Whether the MultiSend at `address` refuses delegatecalls, which is assumed unless it has the code
+/// of a canonical full MultiSend.
+pub(crate) async fn is_multi_send_call_only(provider: &Provider<Http>, address: Address) -> bool {
+    let code_hash = match provider.get_code(address, None).await {
+        Ok(code) if !code.is_empty() => H256::from(keccak256(&code)),
+        _ => return true,
+    };
+    !matches!(canonical_deployment(ContractKind::MultiSend, code_hash), Some((ContractKind::MultiSend, _)))
+}
+
+/// Kind and version of the canonical deployment configurable as `kind` whose code hashes to `code_hash`.
+/// Only the embedded hashes count, code at the canonical addresses proves nothing on chains where
+/// anyone could have deployed something else there.
+fn canonical_deployment(kind: ContractKind, code_hash: H256) -> Option<(ContractKind, String)> {
+    canonical_code_hashes().into_iter()
+        .find(|known| kind.accepts(known.kind) && known.code_hash == code_hash)
+        .map(|known| (known.kind, known.version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "abi/canonical_code_hashes.json has to be generated with --print-code-hashes first"]
+    fn embedded_code_hashes_cover_every_kind() {
+        let code_hashes = canonical_code_hashes();
+        assert!(!code_hashes.is_empty());
+        for kind in [ContractKind::MasterCopy, ContractKind::ProxyFactory, ContractKind::FallbackHandler,
+                     ContractKind::MultiSend, ContractKind::MultiSendCallOnly, ContractKind::Multicall] {
+            assert!(code_hashes.iter().any(|known| known.kind == kind), "no code hash of {kind}");
+        }
+    }
+
+    #[test]
+    fn multi_send_addresses_accept_the_call_only_variant() {
+        assert!(ContractKind::MultiSend.accepts(ContractKind::MultiSendCallOnly));
+        assert!(!ContractKind::MultiSendCallOnly.accepts(ContractKind::MultiSend));
+        assert!(!ContractKind::MasterCopy.accepts(ContractKind::ProxyFactory));
+    }
+}
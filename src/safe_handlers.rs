@@ -1,13 +1,18 @@
+use std::sync::Arc;
+
 use actix_web::{get, post, put, ResponseError};
 use actix_web::body::BoxBody;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
 use actix_web::Responder;
 use actix_web::web;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-use crate::safe::SafeError;
+use crate::safe::{BatchCall, ModuleChange, OwnerChange, SafeError, SafeSetup};
+use crate::safe_sponsor::Sponsee;
+use crate::safe_tenant::Tenant;
 use crate::safe_use_case::SafeUseCase;
 
 type SafeResult<R> = Result<R, SafeError>;
@@ -22,22 +27,222 @@ pub(crate) struct SafeErr {
 #[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SafeCall {
+    // a single call, or else `calls` run through MultiSend
+    #[serde(default)]
     to: String,
+    #[serde(default = "zero")]
     value: String,
+    #[serde(default)]
     data: Vec<u8>,
+    #[serde(default)]
     operation: u8,
+    #[serde(default)]
+    calls: Vec<BatchCall>,
     safe_tx_gas: String,
     base_gas: String,
     gas_price: String,
     gas_token: String,
     refund_receiver: String,
     signatures: Vec<u8>,
+    #[serde(default)]
+    owners: Vec<String>,
+    threshold: Option<usize>,
+    salt_nonce: Option<String>,
+    // deploys a counterfactual Safe first, in the same transaction through the multicall contract
+    #[serde(default)]
+    deploy_if_needed: bool,
+    // EIP-4361 message with a nonce from `/v1/auth/nonce`, signed by the user, for `deploy_if_needed`
+    message: Option<String>,
+    signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeTxParams {
+    // a single call, or else `calls` run through MultiSend
+    #[serde(default)]
+    to: String,
+    #[serde(default = "zero")]
+    value: String,
+    #[serde(default)]
+    data: Vec<u8>,
+    #[serde(default)]
+    operation: u8,
+    #[serde(default)]
+    calls: Vec<BatchCall>,
+    safe_tx_gas: String,
+    base_gas: String,
+    gas_price: String,
+    gas_token: String,
+    refund_receiver: String,
+    #[serde(default)]
+    owners: Vec<String>,
+    threshold: Option<usize>,
+    salt_nonce: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeEstimate {
+    // a single call, or else `calls` run through MultiSend
+    #[serde(default)]
+    to: String,
+    #[serde(default = "zero")]
+    value: String,
+    #[serde(default)]
+    data: Vec<u8>,
+    #[serde(default)]
+    operation: u8,
+    #[serde(default)]
+    calls: Vec<BatchCall>,
+    #[serde(default = "ether")]
+    gas_token: String,
+    #[serde(default)]
+    owners: Vec<String>,
+    threshold: Option<usize>,
+    salt_nonce: Option<String>,
+}
+
+fn ether() -> String {
+    "0x0000000000000000000000000000000000000000".to_string()
+}
+
+fn zero() -> String {
+    "0".to_string()
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeDeploy {
+    #[serde(default)]
+    owners: Vec<String>,
+    threshold: Option<usize>,
+    salt_nonce: Option<String>,
+    // EIP-4361 message with a nonce from `/v1/auth/nonce`, signed by the user
+    message: Option<String>,
+    signature: Option<String>,
+}
+
+/// Adds `owner`, the threshold stays the same when omitted.
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AddOwner {
+    owner: String,
+    threshold: Option<usize>,
+    #[serde(default = "ether")]
+    gas_token: String,
+}
+
+/// Removes `owner`, the threshold stays the same when omitted unless it would exceed the remaining owners.
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RemoveOwner {
+    owner: String,
+    threshold: Option<usize>,
+    #[serde(default = "ether")]
+    gas_token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SwapOwner {
+    old_owner: String,
+    new_owner: String,
+    #[serde(default = "ether")]
+    gas_token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ChangeThreshold {
+    threshold: usize,
+    #[serde(default = "ether")]
+    gas_token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ModuleParams {
+    module: String,
+    #[serde(default = "ether")]
+    gas_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SafeQuery {
+    owners: Option<String>,
+    threshold: Option<usize>,
+    salt_nonce: Option<String>,
+}
+
+/// Routes are served under `/v1` for the default chain and under `/v1/{chain_id}`.
+#[derive(Deserialize)]
+pub(crate) struct ChainPath {
+    #[serde(default)]
+    chain_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SafePath {
+    #[serde(default)]
+    chain_id: Option<u64>,
+    address: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RelayPath {
+    #[serde(default)]
+    chain_id: Option<u64>,
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ModulePage {
+    start: Option<String>,
+    count: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SafeRange {
+    from: Option<u64>,
+    count: Option<u64>,
+}
+
+impl From<SafeQuery> for SafeSetup {
+    fn from(query: SafeQuery) -> Self {
+        let owners = query.owners
+            .map(|owners| owners.split(',').map(|owner| owner.trim().to_string()).collect())
+            .unwrap_or_default();
+        Self {
+            owners,
+            threshold: query.threshold,
+            salt_nonce: query.salt_nonce,
+            fallback_handler: None,
+        }
+    }
+}
+
+impl From<&SafeDeploy> for SafeSetup {
+    fn from(deploy: &SafeDeploy) -> Self {
+        Self {
+            owners: deploy.owners.clone(),
+            threshold: deploy.threshold,
+            salt_nonce: deploy.salt_nonce.clone(),
+            fallback_handler: None,
+        }
+    }
 }
 
 impl ResponseError for SafeError {
     fn status_code(&self) -> StatusCode {
         match self {
-            SafeError::RpcError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            SafeError::RpcError(_) | SafeError::RelayerUnderfunded => StatusCode::SERVICE_UNAVAILABLE,
+            SafeError::ExecutionReverted(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            SafeError::UnknownRelay(_) | SafeError::UnsupportedChain(_) => StatusCode::NOT_FOUND,
+            SafeError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            SafeError::Forbidden(_) => StatusCode::FORBIDDEN,
+            SafeError::QuotaExceeded(_) | SafeError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::BAD_REQUEST
         }
     }
@@ -47,10 +252,11 @@ impl ResponseError for SafeError {
             code: self.status_code().as_u16(),
             message: format!("{self}"),
         };
-        match self {
-            SafeError::RpcError(_) => HttpResponse::ServiceUnavailable(),
-            _ => HttpResponse::BadRequest(),
-        }.json(json)
+        let mut response = HttpResponse::build(self.status_code());
+        if let SafeError::RateLimited(retry_after) = self {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        response.json(json)
     }
 }
 
@@ -65,65 +271,170 @@ responses(
 ),
 params(
 ("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "number of required confirmations, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
 )
 )]
-#[get("/v1/safe/{address}")]
-pub(crate) async fn calculate_address(address: web::Path<String>, service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
-    let address = address.into_inner();
-    let response = service.info(address.as_str()).await?;
+#[get("/safe/{address}")]
+pub(crate) async fn calculate_address(path: web::Path<SafePath>,
+                                      query: web::Query<SafeQuery>,
+                                      tenant: web::ReqData<Arc<Tenant>>,
+                                      service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let response = service.info(chain_id, address.as_str(), &setup).await?;
     Ok(
         HttpResponse::Ok().json(response)
     )
 }
 
 #[utoipa::path(
-post,
+get,
 tag = "safe::api",
-path = "/v1/safe/{address}",
+path = "/v1/safe/{address}/state",
+responses(
+(status = 200, description = "owners, threshold, nonce, modules, version, fallback handler and balance of the deployed safe", body = SafeState),
+(status = 400, description = "bad params or safe not deployed", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "number of required confirmations, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+)
+)]
+#[get("/safe/{address}/state")]
+pub(crate) async fn safe_state(path: web::Path<SafePath>,
+                               query: web::Query<SafeQuery>,
+                               tenant: web::ReqData<Arc<Tenant>>,
+                               service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let response = service.state(chain_id, address.as_str(), &setup).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+get,
+tag = "safe::api",
+path = "/v1/safe/{address}/list",
 responses(
-(status = 201, description = "safe response", body = SafeResponse),
+(status = 200, description = "safes of the user for consecutive salt nonces", body = [SafeInfo]),
 (status = 400, description = "bad params", body = SafeErr),
 (status = 503, description = "service unavailable", body = SafeErr)
 ),
 params(
 ("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "number of required confirmations, 1 when omitted"),
+("from" = Option<u64>, Query, description = "first salt nonce, 0 when omitted"),
+("count" = Option<u64>, Query, description = "number of salt nonces to check, 10 when omitted"),
 )
 )]
-#[post("/v1/safe/{address}")]
-pub(crate) async fn deploy_contract(address: web::Path<String>, service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
-    let address = address.into_inner();
-    let response = service.deploy(address.as_str()).await?;
+#[get("/safe/{address}/list")]
+pub(crate) async fn list_safes(path: web::Path<SafePath>,
+                               query: web::Query<SafeQuery>,
+                               range: web::Query<SafeRange>,
+                               tenant: web::ReqData<Arc<Tenant>>,
+                               service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let SafeRange { from, count } = range.into_inner();
+    let response = service.list(chain_id, address.as_str(), &setup, from.unwrap_or(0), count.unwrap_or(10)).await?;
     Ok(
-        HttpResponse::Created().json(response)
+        HttpResponse::Ok().json(response)
     )
 }
 
 #[utoipa::path(
-put,
+post,
 tag = "safe::api",
 path = "/v1/safe/{address}",
 responses(
-(status = 200, description = "safe response", body = SafeResponse),
+(status = 202, description = "deployment relayed, track it by relay id", body = SafeResponse),
 (status = 400, description = "bad params", body = SafeErr),
-(status = 503, description = "service unavailable", body = SafeErr)
+(status = 401, description = "missing or invalid sign-in message", body = SafeErr),
+(status = 422, description = "deployment would revert", body = SafeErr),
+(status = 429, description = "sponsored deployment quota exceeded", body = SafeErr),
+(status = 503, description = "service unavailable or relayer underfunded", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+),
+request_body(content = SafeDeploy, description = "signed sign-in message, with owners, threshold and salt nonce, the user is the only owner when omitted", content_type = "application/json"),
+)]
+#[post("/safe/{address}")]
+pub(crate) async fn deploy_contract(req: HttpRequest,
+                                    path: web::Path<SafePath>,
+                                    params: web::Json<SafeDeploy>,
+                                    tenant: web::ReqData<Arc<Tenant>>,
+                                    service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(&params));
+    let sponsee = Sponsee {
+        // forwarding headers can be forged, so quotas go by the connecting peer
+        client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        message: params.message,
+        signature: params.signature,
+    };
+    let response = service.deploy(chain_id, &tenant, address.as_str(), &setup, &sponsee).await?;
+    Ok(
+        HttpResponse::Accepted().json(response)
+    )
+}
+
+#[utoipa::path(
+put,
+tag = "safe::api",
+path = "/v1/safe/{address}",
+responses(
+(status = 202, description = "transaction relayed, track it by relay id, with the deployment's relay when deployed first", body = SafeResponse),
+(status = 400, description = "bad params or refund not accepted", body = SafeErr),
+(status = 401, description = "missing or invalid sign-in message when deploying first", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 429, description = "sponsored deployment quota exceeded", body = SafeErr),
+(status = 503, description = "service unavailable or relayer underfunded", body = SafeErr)
 ),
 params(
 ("address" = String, Path, description = "user's public address"),
 ),
 request_body(content = SafeCall, description = "safe operation request", content_type = "application/json"),
 )]
-#[put("/v1/safe/{address}")]
-pub(crate) async fn exec_transaction(address: web::Path<String>,
+#[put("/safe/{address}")]
+pub(crate) async fn exec_transaction(req: HttpRequest,
+                                     path: web::Path<SafePath>,
                                      params: web::Json<SafeCall>,
+                                     tenant: web::ReqData<Arc<Tenant>>,
                                      service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
-    let address = address.into_inner();
+    let SafePath { chain_id, address } = path.into_inner();
     let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup {
+        owners: params.owners,
+        threshold: params.threshold,
+        salt_nonce: params.salt_nonce,
+        fallback_handler: None,
+    });
+    let sponsee = Sponsee {
+        client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        message: params.message,
+        signature: params.signature,
+    };
+    let deploy_if_needed = params.deploy_if_needed.then(|| (&**tenant, &sponsee));
     let response = service.exec(
+        chain_id,
+        deploy_if_needed,
         address.as_str(),
+        &setup,
         &params.to,
         &params.value,
         params.data,
         params.operation,
+        &params.calls,
         &params.safe_tx_gas,
         &params.base_gas,
         &params.gas_price,
@@ -131,7 +442,424 @@ pub(crate) async fn exec_transaction(address: web::Path<String>,
         &params.refund_receiver,
         params.signatures,
     ).await?;
+    Ok(
+        HttpResponse::Accepted().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/tx-hash",
+responses(
+(status = 200, description = "SafeTx hash and EIP-712 typed data to sign, for nonce 0 of a safe not deployed yet", body = SafeTxHash),
+(status = 400, description = "bad params", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+),
+request_body(content = SafeTxParams, description = "safe operation to sign", content_type = "application/json"),
+)]
+#[post("/safe/{address}/tx-hash")]
+pub(crate) async fn transaction_hash(path: web::Path<SafePath>,
+                                     params: web::Json<SafeTxParams>,
+                                     tenant: web::ReqData<Arc<Tenant>>,
+                                     service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup {
+        owners: params.owners,
+        threshold: params.threshold,
+        salt_nonce: params.salt_nonce,
+        fallback_handler: None,
+    });
+    let response = service.tx_hash(
+        chain_id,
+        address.as_str(),
+        &setup,
+        &params.to,
+        &params.value,
+        params.data,
+        params.operation,
+        &params.calls,
+        &params.safe_tx_gas,
+        &params.base_gas,
+        &params.gas_price,
+        &params.gas_token,
+        &params.refund_receiver,
+    ).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/estimate",
+responses(
+(status = 200, description = "refund parameters the relayer accepts", body = SafeFeeQuote),
+(status = 400, description = "bad params or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+),
+request_body(content = SafeEstimate, description = "safe operation to quote", content_type = "application/json"),
+)]
+#[post("/safe/{address}/estimate")]
+pub(crate) async fn estimate_fee(path: web::Path<SafePath>,
+                                 params: web::Json<SafeEstimate>,
+                                 tenant: web::ReqData<Arc<Tenant>>,
+                                 service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup {
+        owners: params.owners,
+        threshold: params.threshold,
+        salt_nonce: params.salt_nonce,
+        fallback_handler: None,
+    });
+    let response = service.estimate(
+        chain_id,
+        address.as_str(),
+        &setup,
+        &params.to,
+        &params.value,
+        params.data,
+        params.operation,
+        &params.calls,
+        &params.gas_token,
+    ).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/owners/add",
+responses(
+(status = 200, description = "addOwnerWithThreshold call of the safe, ready to sign", body = SafeTxPayload),
+(status = 400, description = "bad params, safe not deployed or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+),
+request_body(content = AddOwner, description = "addOwnerWithThreshold call of the safe", content_type = "application/json"),
+)]
+#[post("/safe/{address}/owners/add")]
+pub(crate) async fn add_owner(path: web::Path<SafePath>,
+                                    query: web::Query<SafeQuery>,
+                                    params: web::Json<AddOwner>,
+                                    tenant: web::ReqData<Arc<Tenant>>,
+                                    service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let change = OwnerChange::Add {
+        owner: params.owner,
+        threshold: params.threshold,
+    };
+    let response = service.owner_tx(chain_id, address.as_str(), &setup, &change, &params.gas_token).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/owners/remove",
+responses(
+(status = 200, description = "removeOwner call of the safe, ready to sign", body = SafeTxPayload),
+(status = 400, description = "bad params, safe not deployed or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+),
+request_body(content = RemoveOwner, description = "removeOwner call of the safe", content_type = "application/json"),
+)]
+#[post("/safe/{address}/owners/remove")]
+pub(crate) async fn remove_owner(path: web::Path<SafePath>,
+                                       query: web::Query<SafeQuery>,
+                                       params: web::Json<RemoveOwner>,
+                                       tenant: web::ReqData<Arc<Tenant>>,
+                                       service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let change = OwnerChange::Remove {
+        owner: params.owner,
+        threshold: params.threshold,
+    };
+    let response = service.owner_tx(chain_id, address.as_str(), &setup, &change, &params.gas_token).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/owners/swap",
+responses(
+(status = 200, description = "swapOwner call of the safe, ready to sign", body = SafeTxPayload),
+(status = 400, description = "bad params, safe not deployed or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+),
+request_body(content = SwapOwner, description = "swapOwner call of the safe", content_type = "application/json"),
+)]
+#[post("/safe/{address}/owners/swap")]
+pub(crate) async fn swap_owner(path: web::Path<SafePath>,
+                                     query: web::Query<SafeQuery>,
+                                     params: web::Json<SwapOwner>,
+                                     tenant: web::ReqData<Arc<Tenant>>,
+                                     service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let change = OwnerChange::Swap {
+        old_owner: params.old_owner,
+        new_owner: params.new_owner,
+    };
+    let response = service.owner_tx(chain_id, address.as_str(), &setup, &change, &params.gas_token).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/owners/threshold",
+responses(
+(status = 200, description = "changeThreshold call of the safe, ready to sign", body = SafeTxPayload),
+(status = 400, description = "bad params, safe not deployed or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+),
+request_body(content = ChangeThreshold, description = "changeThreshold call of the safe", content_type = "application/json"),
+)]
+#[post("/safe/{address}/owners/threshold")]
+pub(crate) async fn change_threshold(path: web::Path<SafePath>,
+                                           query: web::Query<SafeQuery>,
+                                           params: web::Json<ChangeThreshold>,
+                                           tenant: web::ReqData<Arc<Tenant>>,
+                                           service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let change = OwnerChange::ChangeThreshold {
+        threshold: params.threshold,
+    };
+    let response = service.owner_tx(chain_id, address.as_str(), &setup, &change, &params.gas_token).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+get,
+tag = "safe::api",
+path = "/v1/safe/{address}/modules",
+responses(
+(status = 200, description = "a page of the modules enabled on the safe", body = SafeModules),
+(status = 400, description = "bad params or safe not deployed", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+("start" = Option<String>, Query, description = "`next` of the previous page, the first page when omitted"),
+("count" = Option<u64>, Query, description = "page size, 10 when omitted"),
+)
+)]
+#[get("/safe/{address}/modules")]
+pub(crate) async fn list_modules(path: web::Path<SafePath>,
+                                 query: web::Query<SafeQuery>,
+                                 page: web::Query<ModulePage>,
+                                 tenant: web::ReqData<Arc<Tenant>>,
+                                 service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let ModulePage { start, count } = page.into_inner();
+    let response = service.modules(chain_id, address.as_str(), &setup, start.as_deref(), count.unwrap_or(10)).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/modules/enable",
+responses(
+(status = 200, description = "enableModule call of the safe, ready to sign", body = SafeTxPayload),
+(status = 400, description = "bad params, safe not deployed or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+),
+request_body(content = ModuleParams, description = "module to enable", content_type = "application/json"),
+)]
+#[post("/safe/{address}/modules/enable")]
+pub(crate) async fn enable_module(path: web::Path<SafePath>,
+                                        query: web::Query<SafeQuery>,
+                                        params: web::Json<ModuleParams>,
+                                        tenant: web::ReqData<Arc<Tenant>>,
+                                        service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let change = ModuleChange::Enable {
+        module: params.module,
+    };
+    let response = service.module_tx(chain_id, address.as_str(), &setup, &change, &params.gas_token).await?;
     Ok(
         HttpResponse::Ok().json(response)
     )
 }
+
+#[utoipa::path(
+post,
+tag = "safe::api",
+path = "/v1/safe/{address}/modules/disable",
+responses(
+(status = 200, description = "disableModule call of the safe, with the prevModule it needs, ready to sign", body = SafeTxPayload),
+(status = 400, description = "bad params, safe not deployed or gas token not accepted", body = SafeErr),
+(status = 422, description = "transaction would revert", body = SafeErr),
+(status = 503, description = "service unavailable", body = SafeErr)
+),
+params(
+("address" = String, Path, description = "user's public address"),
+("owners" = Option<String>, Query, description = "comma separated owners the safe was created with, the user is the only owner when omitted"),
+("threshold" = Option<usize>, Query, description = "threshold the safe was created with, 1 when omitted"),
+("saltNonce" = Option<String>, Query, description = "decimal salt nonce, the configured one when omitted"),
+),
+request_body(content = ModuleParams, description = "module to disable", content_type = "application/json"),
+)]
+#[post("/safe/{address}/modules/disable")]
+pub(crate) async fn disable_module(path: web::Path<SafePath>,
+                                         query: web::Query<SafeQuery>,
+                                         params: web::Json<ModuleParams>,
+                                         tenant: web::ReqData<Arc<Tenant>>,
+                                         service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let SafePath { chain_id, address } = path.into_inner();
+    let params = params.into_inner();
+    let setup = tenant.setup(SafeSetup::from(query.into_inner()));
+    let change = ModuleChange::Disable {
+        module: params.module,
+    };
+    let response = service.module_tx(chain_id, address.as_str(), &setup, &change, &params.gas_token).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+get,
+tag = "safe::api",
+path = "/v1/auth/nonce",
+responses(
+(status = 200, description = "single use nonce for the EIP-4361 sign-in message", body = AuthNonce),
+)
+)]
+#[get("/auth/nonce")]
+pub(crate) async fn auth_nonce(service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let response = service.auth_nonce();
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+get,
+tag = "safe::api",
+path = "/v1/relay/{id}",
+responses(
+(status = 200, description = "relay status", body = RelayStatus),
+(status = 404, description = "unknown relay", body = SafeErr)
+),
+params(
+("id" = String, Path, description = "relay id returned by deploy or exec"),
+)
+)]
+#[get("/relay/{id}")]
+pub(crate) async fn relay_status(path: web::Path<RelayPath>, service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let RelayPath { chain_id, id } = path.into_inner();
+    let response = service.relay(chain_id, id.as_str()).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+#[utoipa::path(
+get,
+tag = "safe::api",
+path = "/v1/health",
+responses(
+(status = 200, description = "relayer balances and whether relays are accepted", body = RelayerHealth),
+)
+)]
+#[get("/health")]
+pub(crate) async fn health(path: web::Path<ChainPath>, service: web::Data<SafeUseCase>) -> SafeResult<impl Responder> {
+    let response = service.health(path.into_inner().chain_id).await?;
+    Ok(
+        HttpResponse::Ok().json(response)
+    )
+}
+
+/// Registers every API route, mounted once per `/v1` scope.
+pub(crate) fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_safes)
+        .service(calculate_address)
+        .service(safe_state)
+        .service(deploy_contract)
+        .service(exec_transaction)
+        .service(transaction_hash)
+        .service(estimate_fee)
+        .service(add_owner)
+        .service(remove_owner)
+        .service(swap_owner)
+        .service(change_threshold)
+        .service(list_modules)
+        .service(enable_module)
+        .service(disable_module)
+        .service(auth_nonce)
+        .service(relay_status)
+        .service(health);
+}
@@ -0,0 +1,134 @@
+use ethers::abi::{decode, encode, ParamType, Token};
+use ethers::types::{Address, Bytes, U256};
+use ethers::utils::{id, to_checksum};
+
+use crate::safe::{BatchCall, SafeError};
+use crate::safe_tx::InnerCall;
+
+const MULTI_SEND_SIGNATURE: &str = "multiSend(bytes)";
+
+// operation, to, value and data length precede the data of every packed call
+const PACKED_HEADER_LENGTH: usize = 1 + 20 + 32 + 32;
+
+/// Encodes the `multiSend(bytes)` call running `calls` one after the other.
+pub(crate) fn encode_multi_send(calls: &[InnerCall]) -> Bytes {
+    let mut transactions = Vec::new();
+    for call in calls {
+        let mut word = [0u8; 32];
+        transactions.push(call.operation);
+        transactions.extend_from_slice(call.to.as_bytes());
+        call.value.to_big_endian(&mut word);
+        transactions.extend_from_slice(&word);
+        U256::from(call.data.len()).to_big_endian(&mut word);
+        transactions.extend_from_slice(&word);
+        transactions.extend_from_slice(&call.data);
+    }
+    Bytes::from([id(MULTI_SEND_SIGNATURE).as_slice(), &encode(&[Token::Bytes(transactions)])].concat())
+}
+
+/// Unpacks `multiSend(bytes)` calldata into the calls it runs.
+pub(crate) fn decode_multi_send(data: &[u8]) -> Result<Vec<InnerCall>, SafeError> {
+    let bad_multi_send = |e: &str| SafeError::BadParams(format!("bad multiSend calldata: {e}"));
+
+    if data.len() < 4 || data[..4] != id(MULTI_SEND_SIGNATURE) {
+        return Err(bad_multi_send("not a multiSend call"));
+    }
+    let transactions = match decode(&[ParamType::Bytes], &data[4..]).map_err(|e| bad_multi_send(&e.to_string()))?.pop() {
+        Some(Token::Bytes(transactions)) => transactions,
+        _ => return Err(bad_multi_send("missing transactions")),
+    };
+
+    let mut calls = Vec::new();
+    let mut rest = transactions.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < PACKED_HEADER_LENGTH {
+            return Err(bad_multi_send("truncated call"));
+        }
+        let data_length = U256::from_big_endian(&rest[53..PACKED_HEADER_LENGTH]);
+        if data_length > U256::from(rest.len() - PACKED_HEADER_LENGTH) {
+            return Err(bad_multi_send("truncated call data"));
+        }
+        let end = PACKED_HEADER_LENGTH + data_length.as_usize();
+        calls.push(InnerCall {
+            to: Address::from_slice(&rest[1..21]),
+            value: U256::from_big_endian(&rest[21..53]),
+            data: Bytes::from(rest[PACKED_HEADER_LENGTH..end].to_vec()),
+            operation: rest[0],
+        });
+        rest = &rest[end..];
+    }
+    Ok(calls)
+}
+
+impl From<&InnerCall> for BatchCall {
+    fn from(call: &InnerCall) -> Self {
+        Self {
+            to: to_checksum(&call.to, None),
+            value: call.value.to_string(),
+            data: call.data.to_vec(),
+            operation: call.operation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calls() -> Vec<InnerCall> {
+        vec![
+            InnerCall {
+                to: Address::repeat_byte(0x11),
+                value: U256::from(1_000),
+                data: Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]),
+                operation: 0,
+            },
+            InnerCall {
+                to: Address::repeat_byte(0x22),
+                value: U256::zero(),
+                data: Bytes::default(),
+                operation: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips() {
+        let decoded = decode_multi_send(&encode_multi_send(&calls())).unwrap();
+
+        assert_eq!(decoded.len(), 2);
+        for (decoded, call) in decoded.iter().zip(calls()) {
+            assert_eq!((decoded.to, decoded.value, &decoded.data, decoded.operation), (call.to, call.value, &call.data, call.operation));
+        }
+    }
+
+    #[test]
+    fn packs_calls_without_padding() {
+        let encoded = encode_multi_send(&calls()[..1]);
+
+        // multiSend(bytes)
+        assert_eq!(&encoded[..4], [0x8d, 0x80, 0xff, 0x0a]);
+        let transactions = match decode(&[ParamType::Bytes], &encoded[4..]).unwrap().pop() {
+            Some(Token::Bytes(transactions)) => transactions,
+            _ => unreachable!(),
+        };
+        assert_eq!(transactions.len(), PACKED_HEADER_LENGTH + 4);
+        assert_eq!(transactions[0], 0);
+        assert_eq!(&transactions[1..21], Address::repeat_byte(0x11).as_bytes());
+        assert_eq!(&transactions[PACKED_HEADER_LENGTH..], [0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn refuses_truncated_calls() {
+        let encoded = encode_multi_send(&calls()[..1]);
+        let transactions = match decode(&[ParamType::Bytes], &encoded[4..]).unwrap().pop() {
+            Some(Token::Bytes(transactions)) => transactions,
+            _ => unreachable!(),
+        };
+        let truncated = |length: usize| [&encoded[..4], encode(&[Token::Bytes(transactions[..length].to_vec())]).as_slice()].concat();
+
+        assert!(decode_multi_send(&truncated(PACKED_HEADER_LENGTH - 1)).is_err());
+        assert!(decode_multi_send(&truncated(PACKED_HEADER_LENGTH + 3)).is_err());
+        assert!(decode_multi_send(&[0, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use ethers::abi::{decode, encode, ParamType, Token};
+use ethers::types::{Address, Bytes};
+use ethers::utils::id;
+
+use crate::safe::SafeError;
+
+const AGGREGATE3_SIGNATURE: &str = "aggregate3((address,bool,bytes)[])";
+
+/// Encodes the Multicall3 `aggregate3` call running `calls` in one transaction,
+/// reverting all of them when any one reverts.
+pub(crate) fn encode_aggregate3(calls: &[(Address, Bytes)]) -> Bytes {
+    let calls = calls.iter()
+        .map(|(target, data)| Token::Tuple(vec![
+            Token::Address(*target),
+            Token::Bool(false), // allowFailure
+            Token::Bytes(data.to_vec()),
+        ]))
+        .collect();
+    Bytes::from([id(AGGREGATE3_SIGNATURE).as_slice(), &encode(&[Token::Array(calls)])].concat())
+}
+
+/// The return data of every call of an `aggregate3` call, in call order.
+pub(crate) fn decode_aggregate3(output: &[u8]) -> Result<Vec<Bytes>, SafeError> {
+    let bad_output = |e: &str| SafeError::RpcError(format!("bad aggregate3 output: {e}"));
+
+    let result_type = ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::Bool, ParamType::Bytes])));
+    let results = match decode(&[result_type], output).map_err(|e| bad_output(&e.to_string()))?.pop() {
+        Some(Token::Array(results)) => results,
+        _ => return Err(bad_output("missing results")),
+    };
+    results.into_iter()
+        .map(|result| match result {
+            Token::Tuple(mut fields) => match fields.pop() {
+                Some(Token::Bytes(data)) => Ok(Bytes::from(data)),
+                _ => Err(bad_output("missing return data")),
+            },
+            _ => Err(bad_output("result is not a tuple")),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_aggregate3_without_allowing_failures() {
+        let calls = [(Address::repeat_byte(0x11), Bytes::from(vec![0x01, 0x02])), (Address::repeat_byte(0x22), Bytes::default())];
+        let encoded = encode_aggregate3(&calls);
+
+        assert_eq!(&encoded[..4], [0x82, 0xad, 0x56, 0xcb]);
+        let call_type = ParamType::Tuple(vec![ParamType::Address, ParamType::Bool, ParamType::Bytes]);
+        let decoded = decode(&[ParamType::Array(Box::new(call_type))], &encoded[4..]).unwrap();
+        assert_eq!(decoded, vec![Token::Array(vec![
+            Token::Tuple(vec![Token::Address(Address::repeat_byte(0x11)), Token::Bool(false), Token::Bytes(vec![0x01, 0x02])]),
+            Token::Tuple(vec![Token::Address(Address::repeat_byte(0x22)), Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+    }
+
+    #[test]
+    fn decodes_return_data_in_call_order() {
+        let output = encode(&[Token::Array(vec![
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(vec![0xaa])]),
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(vec![0xbb, 0xcc])]),
+        ])]);
+
+        assert_eq!(decode_aggregate3(&output).unwrap(), vec![Bytes::from(vec![0xaa]), Bytes::from(vec![0xbb, 0xcc])]);
+        assert!(matches!(decode_aggregate3(&[0x01]), Err(SafeError::RpcError(_))));
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use ethers::types::{Address, U256};
+
+use crate::safe::SafeError;
+
+const TX_BASE_GAS: u64 = 21_000;
+
+// nonce update, event and bookkeeping of `execTransaction` itself
+const EXEC_OVERHEAD_GAS: u64 = 15_000;
+
+// `ecrecover` plus the owner lookup in `checkSignatures`
+const SIGNATURE_GAS: u64 = 7_000;
+
+// `handlePayment` sending ether or calling `transfer` on the gas token
+const ETHER_REFUND_GAS: u64 = 10_000;
+const TOKEN_REFUND_GAS: u64 = 50_000;
+
+// `ecrecover` and 65 non-zero bytes of calldata instead of an `approvedHashes` lookup
+pub(crate) const ECDSA_OVER_APPROVAL_GAS: u64 = 3_000;
+
+/// Prices gas in the token the Safe refunds the relayer with.
+#[async_trait]
+pub(crate) trait GasPriceOracle: Send + Sync {
+    /// Gas price in units of `gas_token` matching `ether_gas_price` in wei,
+    /// the zero address being ether.
+    async fn gas_price(&self, gas_token: Address, ether_gas_price: U256) -> Result<U256, SafeError>;
+}
+
+/// Fixed token prices, as token units worth one ether.
+pub(crate) struct StaticPriceTable {
+    prices: HashMap<Address, U256>,
+}
+
+#[async_trait]
+impl GasPriceOracle for StaticPriceTable {
+    async fn gas_price(&self, gas_token: Address, ether_gas_price: U256) -> Result<U256, SafeError> {
+        if gas_token.is_zero() {
+            return Ok(ether_gas_price);
+        }
+        let price = self.prices.get(&gas_token)
+            .ok_or_else(|| SafeError::RefundRejected(format!("gas token {gas_token:?} has no price")))?;
+        Ok(ether_gas_price * price / U256::exp10(18))
+    }
+}
+
+/// Parses `token:unitsPerEther` pairs separated by commas, empty for ether only.
+impl FromStr for StaticPriceTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prices = s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (token, price) = entry.trim().split_once(':')
+                    .ok_or_else(|| format!("Gas token price {entry} must be token:unitsPerEther"))?;
+                let token = token.parse::<Address>().map_err(|e| format!("Gas token {token}: {e}"))?;
+                let price = U256::from_dec_str(price).map_err(|e| format!("Gas token price {price}: {e}"))?;
+                Ok((token, price))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        Ok(Self { prices })
+    }
+}
+
+/// Gas `execTransaction` spends outside of the inner call, which is what `baseGas` refunds.
+/// `calldata` is the encoded `execTransaction` call, with room for the signatures.
+pub(crate) fn base_gas(calldata: &[u8], threshold: usize, gas_token: Address, safe_tx_gas: U256) -> U256 {
+    // calldata pricing per EIP-2028
+    let calldata_gas: u64 = calldata.iter().map(|byte| if *byte == 0 { 4 } else { 16 }).sum();
+    let refund_gas = if gas_token.is_zero() { ETHER_REFUND_GAS } else { TOKEN_REFUND_GAS };
+    // the Safe wants `max(safeTxGas * 64 / 63, safeTxGas + 2500) + 500` left before the inner call,
+    // so a gas estimate of the whole transaction includes that reserve
+    let reserve = (safe_tx_gas / 63).max(U256::from(2_500)) + 500;
+    U256::from(TX_BASE_GAS + EXEC_OVERHEAD_GAS + calldata_gas + SIGNATURE_GAS * threshold as u64 + refund_gas) + reserve
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+
+    fn fixed_gas(threshold: u64, refund_gas: u64) -> u64 {
+        TX_BASE_GAS + EXEC_OVERHEAD_GAS + SIGNATURE_GAS * threshold + refund_gas + 2_500 + 500
+    }
+
+    #[test]
+    fn prices_zero_and_non_zero_calldata_bytes() {
+        let calldata = [0, 0, 0, 1, 2];
+
+        assert_eq!(base_gas(&calldata, 1, Address::zero(), U256::zero()), U256::from(fixed_gas(1, ETHER_REFUND_GAS) + 3 * 4 + 2 * 16));
+    }
+
+    #[test]
+    fn scales_with_the_signatures() {
+        let one = base_gas(&[], 1, Address::zero(), U256::zero());
+        let three = base_gas(&[], 3, Address::zero(), U256::zero());
+
+        assert_eq!(three - one, U256::from(2 * SIGNATURE_GAS));
+    }
+
+    #[test]
+    fn token_refunds_cost_more_than_ether() {
+        let ether = base_gas(&[], 1, Address::zero(), U256::zero());
+        let token = base_gas(&[], 1, TOKEN.parse().unwrap(), U256::zero());
+
+        assert_eq!(token - ether, U256::from(TOKEN_REFUND_GAS - ETHER_REFUND_GAS));
+    }
+
+    #[test]
+    fn reserves_what_the_safe_keeps_back_from_the_inner_call() {
+        let small = base_gas(&[], 1, Address::zero(), U256::from(63_000));
+        let large = base_gas(&[], 1, Address::zero(), U256::from(630_000));
+
+        // 2500 up to a safeTxGas of 157500, then safeTxGas / 63
+        assert_eq!(small, U256::from(fixed_gas(1, ETHER_REFUND_GAS)));
+        assert_eq!(large, U256::from(fixed_gas(1, ETHER_REFUND_GAS) - 2_500 + 10_000));
+    }
+
+    #[tokio::test]
+    async fn converts_gas_prices_into_token_units() {
+        let prices = format!("{TOKEN}:2000000000000000000000").parse::<StaticPriceTable>().unwrap();
+
+        // 2000 tokens of 18 decimals per ether
+        assert_eq!(prices.gas_price(TOKEN.parse().unwrap(), U256::from(10_000_000_000u64)).await.unwrap(), U256::from(20_000_000_000_000u64));
+        assert_eq!(prices.gas_price(Address::zero(), U256::from(10)).await.unwrap(), U256::from(10));
+        assert!(matches!(prices.gas_price(Address::repeat_byte(0x99), U256::from(10)).await, Err(SafeError::RefundRejected(_))));
+    }
+
+    #[test]
+    fn parses_price_tables() {
+        assert!("".parse::<StaticPriceTable>().is_ok());
+        assert!(format!("{TOKEN}:1").parse::<StaticPriceTable>().is_ok());
+        assert!(TOKEN.parse::<StaticPriceTable>().is_err());
+        assert!("0x12:1".parse::<StaticPriceTable>().is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+
+use crate::safe::SafeError;
+
+// idle buckets are dropped once they have refilled completely
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Requests refill at a steady rate, bursting up to a minute's worth.
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn per_minute(limit: u32) -> Self {
+        Self {
+            capacity: limit as f64,
+            refill_per_sec: limit as f64 / 60.0,
+            tokens: limit as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.updated_at.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated_at = Instant::now();
+    }
+
+    /// Time until a token is available, `None` when there is one now.
+    fn wait(&mut self) -> Option<Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        if self.refill_per_sec <= 0.0 {
+            return Some(Duration::from_secs(60));
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec))
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    /// Takes a token, or tells how long until one is available.
+    pub(crate) fn take(&mut self) -> Result<(), Duration> {
+        match self.wait() {
+            Some(wait) => Err(wait),
+            None => {
+                self.tokens -= 1.0;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum RateClass {
+    Read,
+    Deploy,
+    Exec,
+}
+
+impl RateClass {
+    /// Deploy and exec are the routes the relayer pays for, everything else only reads.
+    fn of(req: &HttpRequest) -> Self {
+        match (req.method(), api_route(req.path()).as_deref()) {
+            (&Method::POST, Some(["safe", _])) => RateClass::Deploy,
+            (&Method::PUT, Some(["safe", _])) => RateClass::Exec,
+            _ => RateClass::Read,
+        }
+    }
+}
+
+/// Requests per minute of every class, applied to each client IP, API key and Safe owner address.
+#[derive(Clone, Copy)]
+pub(crate) struct RateLimits {
+    pub(crate) read: u32,
+    pub(crate) deploy: u32,
+    pub(crate) exec: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum RateKey {
+    Ip(String),
+    ApiKey(String),
+    Safe(String),
+}
+
+struct Buckets {
+    buckets: HashMap<(RateClass, RateKey), TokenBucket>,
+    cleaned_at: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                cleaned_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from every bucket the request falls into, or none when one of them is empty.
+    /// Expects an authenticated request, `api_key` only when it belongs to a tenant, so that made up
+    /// keys and addresses in rejected requests neither get buckets nor drain anyone else's.
+    pub(crate) fn check(&self, req: &HttpRequest, api_key: Option<&str>) -> Result<(), SafeError> {
+        if api_route(req.path()).is_none() {
+            return Ok(());
+        }
+        let class = RateClass::of(req);
+        let limit = match class {
+            RateClass::Read => self.limits.read,
+            RateClass::Deploy => self.limits.deploy,
+            RateClass::Exec => self.limits.exec,
+        };
+
+        let mut keys = Vec::with_capacity(3);
+        // forwarding headers can be forged, so limits go by the connecting peer
+        if let Some(addr) = req.peer_addr() {
+            keys.push(RateKey::Ip(addr.ip().to_string()));
+        }
+        if let Some(api_key) = api_key {
+            keys.push(RateKey::ApiKey(api_key.to_string()));
+        }
+        if let Some(address) = safe_address(req) {
+            keys.push(RateKey::Safe(address));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.cleaned_at.elapsed() >= CLEANUP_INTERVAL {
+            buckets.buckets.retain(|_, bucket| !bucket.is_full());
+            buckets.cleaned_at = Instant::now();
+        }
+        let wait = keys.iter()
+            .filter_map(|key| buckets.buckets
+                .entry((class, key.clone()))
+                .or_insert_with(|| TokenBucket::per_minute(limit))
+                .wait())
+            .max();
+        if let Some(wait) = wait {
+            return Err(SafeError::RateLimited(wait.as_secs_f64().ceil() as u64));
+        }
+        for key in keys {
+            if let Some(bucket) = buckets.buckets.get_mut(&(class, key)) {
+                let _ = bucket.take();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Segments of an API path after `/v1` and the optional chain id, `None` outside of the API.
+pub(crate) fn api_route(path: &str) -> Option<Vec<&str>> {
+    let mut segments = path.trim_matches('/').split('/');
+    if segments.next() != Some("v1") {
+        return None;
+    }
+    let segments: Vec<&str> = segments.collect();
+    match segments.first() {
+        Some(chain_id) if chain_id.parse::<u64>().is_ok() => Some(segments[1..].to_vec()),
+        _ => Some(segments),
+    }
+}
+
+/// The user address of `/v1/safe/{address}` routes, lowercased so any casing shares a bucket.
+fn safe_address(req: &HttpRequest) -> Option<String> {
+    match api_route(req.path()).as_deref() {
+        Some(["safe", address, ..]) if !address.is_empty() => Some(address.to_lowercase()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn request(method: Method, path: &str, ip: &str) -> HttpRequest {
+        TestRequest::default()
+            .method(method)
+            .uri(path)
+            .peer_addr(format!("{ip}:40000").parse().unwrap())
+            .to_http_request()
+    }
+
+    /// Winds the bucket's clock back as if `secs` had passed.
+    fn elapse(bucket: &mut TokenBucket, secs: u64) {
+        bucket.updated_at = bucket.updated_at.checked_sub(Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn buckets_burst_up_to_their_capacity() {
+        let mut bucket = TokenBucket::per_minute(3);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > Duration::from_secs(19) && wait <= Duration::from_secs(20), "{wait:?}");
+    }
+
+    #[test]
+    fn buckets_refill_steadily_up_to_their_capacity() {
+        let mut bucket = TokenBucket::per_minute(60);
+        for _ in 0..60 {
+            bucket.take().unwrap();
+        }
+        assert!(bucket.take().is_err());
+
+        elapse(&mut bucket, 2);
+        assert_eq!(bucket.take(), Ok(()));
+        assert_eq!(bucket.take(), Ok(()));
+        assert!(bucket.take().is_err());
+        assert!(!bucket.is_full());
+
+        elapse(&mut bucket, 3600);
+        assert!(bucket.is_full());
+        for _ in 0..60 {
+            bucket.take().unwrap();
+        }
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn empty_buckets_never_refill() {
+        let mut bucket = TokenBucket::per_minute(0);
+        assert_eq!(bucket.take(), Err(Duration::from_secs(60)));
+        elapse(&mut bucket, 3600);
+        assert_eq!(bucket.take(), Err(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn api_routes() {
+        let cases: [(&str, Option<Vec<&str>>); 9] = [
+            ("/v1/safe/0xab", Some(vec!["safe", "0xab"])),
+            ("/v1/5/safe/0xab", Some(vec!["safe", "0xab"])),
+            ("/v1/5/safe/0xab/tx/", Some(vec!["safe", "0xab", "tx"])),
+            ("v1/health", Some(vec!["health"])),
+            ("/v1/mainnet/safe", Some(vec!["mainnet", "safe"])),
+            ("/v1", Some(vec![])),
+            ("/v2/safe/0xab", None),
+            ("/swagger/index.html", None),
+            ("/", None),
+        ];
+        for (path, route) in cases {
+            assert_eq!(api_route(path), route, "{path}");
+        }
+    }
+
+    #[test]
+    fn safe_addresses() {
+        let cases = [
+            ("/v1/safe/0xAbCd", Some("0xabcd")),
+            ("/v1/5/safe/0xAbCd/tx", Some("0xabcd")),
+            ("/v1/safe", None),
+            ("/v1/safe/", None),
+            ("/v1/auth/nonce", None),
+            ("/safe/0xabcd", None),
+        ];
+        for (path, address) in cases {
+            let req = request(Method::GET, path, "10.0.0.1");
+            assert_eq!(safe_address(&req).as_deref(), address, "{path}");
+        }
+    }
+
+    #[test]
+    fn rate_classes() {
+        let cases = [
+            (Method::POST, "/v1/safe/0xab", RateClass::Deploy),
+            (Method::POST, "/v1/5/safe/0xab", RateClass::Deploy),
+            (Method::PUT, "/v1/safe/0xab", RateClass::Exec),
+            (Method::GET, "/v1/safe/0xab", RateClass::Read),
+            (Method::POST, "/v1/safe/0xab/estimate", RateClass::Read),
+            (Method::PUT, "/v1/safe/0xab/tx", RateClass::Read),
+            (Method::POST, "/v1/safe", RateClass::Read),
+        ];
+        for (method, path, class) in cases {
+            let req = request(method.clone(), path, "10.0.0.1");
+            assert_eq!(RateClass::of(&req), class, "{method} {path}");
+        }
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits { read: 2, deploy: 1, exec: 1 })
+    }
+
+    #[test]
+    fn requests_are_limited_per_ip_and_class() {
+        let limiter = limiter();
+        let deploy = |ip| request(Method::POST, &format!("/v1/safe/0x{ip}"), ip);
+        assert!(limiter.check(&deploy("10.0.0.1"), None).is_ok());
+        assert!(matches!(limiter.check(&deploy("10.0.0.1"), None), Err(SafeError::RateLimited(60))));
+        assert!(limiter.check(&deploy("10.0.0.2"), None).is_ok());
+
+        let read = request(Method::GET, "/v1/safe/0xab", "10.0.0.1");
+        assert!(limiter.check(&read, None).is_ok());
+        assert!(limiter.check(&read, None).is_ok());
+        assert!(matches!(limiter.check(&read, None), Err(SafeError::RateLimited(30))));
+
+        let outside = request(Method::GET, "/swagger/index.html", "10.0.0.1");
+        assert!(limiter.check(&outside, None).is_ok());
+    }
+
+    #[test]
+    fn requests_are_limited_per_api_key_and_safe() {
+        let limiter = limiter();
+        assert!(limiter.check(&request(Method::PUT, "/v1/safe/0xAB", "10.0.0.1"), Some("key")).is_ok());
+        // the Safe's bucket is shared by any casing of the address
+        assert!(limiter.check(&request(Method::PUT, "/v1/5/safe/0xab", "10.0.0.2"), None).is_err());
+        assert!(limiter.check(&request(Method::PUT, "/v1/safe/0xcd", "10.0.0.3"), Some("key")).is_err());
+        assert!(limiter.check(&request(Method::PUT, "/v1/safe/0xcd", "10.0.0.3"), Some("other key")).is_ok());
+    }
+
+    #[test]
+    fn rejected_requests_take_no_tokens() {
+        let limiter = limiter();
+        assert!(limiter.check(&request(Method::PUT, "/v1/safe/0xab", "10.0.0.1"), None).is_ok());
+        // the IP is limited, so the other Safe's bucket is left alone
+        assert!(limiter.check(&request(Method::PUT, "/v1/safe/0xcd", "10.0.0.1"), None).is_err());
+        assert!(limiter.check(&request(Method::PUT, "/v1/safe/0xcd", "10.0.0.2"), None).is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use ethers::types::{Address, U256};
+
+use crate::safe::SafeError;
+use crate::safe_relayer::RelayerPool;
+use crate::safe_tx::SafeTx;
+
+/// What a Safe has to pay back for the relayer to execute its transaction.
+pub(crate) struct RefundPolicy {
+    // minimum gas price per accepted gas token, the zero address being ether
+    min_gas_prices: HashMap<Address, U256>,
+}
+
+impl RefundPolicy {
+    /// Checks who receives the refund and in which token it is paid. Ether refunds are capped at
+    /// `tx.gasprice`, so they have to cover the `network_gas_price` the relayer pays.
+    pub(crate) fn check_payment(&self, safe_tx: &SafeTx, relayers: &RelayerPool, network_gas_price: U256) -> Result<(), SafeError> {
+        // the zero address pays `tx.origin`, which is always the relayer
+        if !safe_tx.refund_receiver.is_zero() && !relayers.contains(safe_tx.refund_receiver) {
+            return Err(SafeError::RefundRejected(format!(
+                "refund receiver {:?} is not a relayer", safe_tx.refund_receiver
+            )));
+        }
+        let mut min_gas_price = self.min_gas_price(safe_tx.gas_token)?;
+        if safe_tx.gas_token.is_zero() {
+            min_gas_price = min_gas_price.max(network_gas_price);
+        }
+        if safe_tx.gas_price < min_gas_price {
+            return Err(SafeError::RefundRejected(format!(
+                "gas price {} is below {min_gas_price} for gas token {:?}", safe_tx.gas_price, safe_tx.gas_token
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn min_gas_price(&self, gas_token: Address) -> Result<U256, SafeError> {
+        self.min_gas_prices.get(&gas_token)
+            .copied()
+            .ok_or_else(|| SafeError::RefundRejected(format!("gas token {gas_token:?} is not accepted")))
+    }
+
+    /// Checks the refunded gas covers what the relayer spends on `execTransaction`.
+    pub(crate) fn check_gas(&self, safe_tx: &SafeTx, estimate: U256) -> Result<(), SafeError> {
+        // the Safe then refunds the gas the call ends up using, which is unknown before it is mined
+        if safe_tx.safe_tx_gas.is_zero() {
+            return Err(SafeError::RefundRejected("safeTxGas must be set, a refund of the gas used cannot be checked".to_string()));
+        }
+        let refunded = safe_tx.safe_tx_gas.saturating_add(safe_tx.base_gas);
+        if refunded < estimate {
+            return Err(SafeError::RefundRejected(format!(
+                "safeTxGas + baseGas is {refunded}, transaction needs {estimate}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Parses `token:minGasPrice` pairs separated by commas.
+impl FromStr for RefundPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let min_gas_prices = s.split(',')
+            .map(|entry| {
+                let (token, min_gas_price) = entry.trim().split_once(':')
+                    .ok_or_else(|| format!("Refund gas token {entry} must be token:minGasPrice"))?;
+                let token = token.parse::<Address>().map_err(|e| format!("Refund gas token {token}: {e}"))?;
+                let min_gas_price = U256::from_dec_str(min_gas_price)
+                    .map_err(|e| format!("Refund gas price {min_gas_price}: {e}"))?;
+                Ok((token, min_gas_price))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        Ok(Self { min_gas_prices })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::types::Bytes;
+
+    use super::*;
+    use crate::safe_relayer::{Relayer, RelayerStrategy};
+
+    const TOKEN: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+
+    fn policy() -> RefundPolicy {
+        format!("0x0000000000000000000000000000000000000000:1000, {TOKEN}:5000").parse().unwrap()
+    }
+
+    fn relayers() -> (RelayerPool, Address) {
+        let relayer = Arc::new(Relayer::offline(1, 0, 0));
+        let address = relayer.address();
+        (RelayerPool::new(vec![relayer], RelayerStrategy::RoundRobin, U256::zero()), address)
+    }
+
+    fn safe_tx(gas_token: Address, gas_price: u64, refund_receiver: Address) -> SafeTx {
+        SafeTx {
+            to: Address::repeat_byte(0x11),
+            value: U256::zero(),
+            data: Bytes::default(),
+            operation: 0,
+            safe_tx_gas: U256::from(50_000),
+            base_gas: U256::from(40_000),
+            gas_price: U256::from(gas_price),
+            gas_token,
+            refund_receiver,
+            nonce: U256::zero(),
+        }
+    }
+
+    #[test]
+    fn min_gas_price_is_per_token() {
+        assert_eq!(policy().min_gas_price(Address::zero()).unwrap(), U256::from(1_000));
+        assert_eq!(policy().min_gas_price(TOKEN.parse().unwrap()).unwrap(), U256::from(5_000));
+        assert!(matches!(policy().min_gas_price(Address::repeat_byte(0x99)), Err(SafeError::RefundRejected(_))));
+    }
+
+    #[test]
+    fn accepts_refunds_to_a_relayer_or_tx_origin() {
+        let (relayers, relayer) = relayers();
+
+        assert!(policy().check_payment(&safe_tx(Address::zero(), 1_000, relayer), &relayers, U256::zero()).is_ok());
+        assert!(policy().check_payment(&safe_tx(Address::zero(), 1_000, Address::zero()), &relayers, U256::zero()).is_ok());
+        assert!(policy().check_payment(&safe_tx(TOKEN.parse().unwrap(), 5_000, relayer), &relayers, U256::zero()).is_ok());
+    }
+
+    #[test]
+    fn refuses_other_refund_receivers() {
+        let (relayers, _) = relayers();
+        let safe_tx = safe_tx(Address::zero(), 1_000, Address::repeat_byte(0x22));
+
+        assert!(matches!(policy().check_payment(&safe_tx, &relayers, U256::zero()), Err(SafeError::RefundRejected(_))));
+    }
+
+    #[test]
+    fn refuses_unknown_tokens_and_low_prices() {
+        let (relayers, relayer) = relayers();
+
+        let unknown_token = safe_tx(Address::repeat_byte(0x99), 1_000_000, relayer);
+        assert!(matches!(policy().check_payment(&unknown_token, &relayers, U256::zero()), Err(SafeError::RefundRejected(_))));
+        let below_minimum = safe_tx(TOKEN.parse().unwrap(), 4_999, relayer);
+        assert!(matches!(policy().check_payment(&below_minimum, &relayers, U256::zero()), Err(SafeError::RefundRejected(_))));
+    }
+
+    #[test]
+    fn ether_refunds_cover_the_network_gas_price() {
+        let (relayers, relayer) = relayers();
+        let safe_tx = safe_tx(Address::zero(), 2_000, relayer);
+
+        assert!(policy().check_payment(&safe_tx, &relayers, U256::from(2_000)).is_ok());
+        assert!(matches!(policy().check_payment(&safe_tx, &relayers, U256::from(2_001)), Err(SafeError::RefundRejected(_))));
+        // token prices are converted from the network price by the quote, not here
+        let token_tx = self::safe_tx(TOKEN.parse().unwrap(), 5_000, relayer);
+        assert!(policy().check_payment(&token_tx, &relayers, U256::from(1_000_000)).is_ok());
+    }
+
+    #[test]
+    fn refunded_gas_covers_the_estimate() {
+        let safe_tx = safe_tx(Address::zero(), 1_000, Address::zero());
+
+        assert!(policy().check_gas(&safe_tx, U256::from(90_000)).is_ok());
+        // baseGas too low for the estimate
+        assert!(matches!(policy().check_gas(&safe_tx, U256::from(90_001)), Err(SafeError::RefundRejected(_))));
+    }
+
+    #[test]
+    fn refuses_refunds_of_the_gas_used() {
+        let safe_tx = SafeTx { safe_tx_gas: U256::zero(), base_gas: U256::from(1_000_000), ..safe_tx(Address::zero(), 1_000, Address::zero()) };
+
+        assert!(matches!(policy().check_gas(&safe_tx, U256::from(90_000)), Err(SafeError::RefundRejected(_))));
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use ethers::prelude::*;
+use log::{debug, warn};
+
+use crate::safe::{RelayerHealth, RelayerInfo, SafeError};
+use crate::safe_service::Signer;
+
+/// Hands out nonces of the relayer account locally, so that concurrent relays
+/// never race on the node's view of the account.
+pub(crate) struct NonceManager {
+    address: Address,
+    nonces: Mutex<Nonces>,
+}
+
+struct Nonces {
+    next: U256,
+    // handed out but never broadcast, handed out again before new ones
+    unused: BTreeSet<U256>,
+}
+
+impl NonceManager {
+    pub(crate) async fn new(provider: &Provider<Http>, address: Address) -> Result<Self, ProviderError> {
+        let next = Self::pending_nonce(provider, address).await?;
+        debug!("Relayer {address:?} starts at nonce {next}");
+        Ok(Self {
+            address,
+            nonces: Mutex::new(Nonces {
+                next,
+                unused: BTreeSet::new(),
+            }),
+        })
+    }
+
+    pub(crate) fn next(&self) -> U256 {
+        let mut nonces = self.nonces.lock().unwrap();
+        if let Some(nonce) = nonces.unused.iter().next().copied() {
+            nonces.unused.remove(&nonce);
+            return nonce;
+        }
+        let nonce = nonces.next;
+        nonces.next = nonce + 1;
+        nonce
+    }
+
+    /// Takes back a nonce whose transaction failed to broadcast, filling the gap it would leave.
+    pub(crate) fn reuse(&self, nonce: U256) {
+        self.nonces.lock().unwrap().unused.insert(nonce);
+    }
+
+    /// Goes back to the node's pending nonce, only sound while no handed out nonce is in flight.
+    fn reset(&self, pending: U256) {
+        let mut nonces = self.nonces.lock().unwrap();
+        if nonces.next != pending {
+            warn!("Relayer {:?} nonce resynced from {} to {}", self.address, nonces.next, pending);
+        }
+        nonces.next = pending;
+        nonces.unused.clear();
+    }
+
+    async fn pending_nonce(provider: &Provider<Http>, address: Address) -> Result<U256, ProviderError> {
+        provider.get_transaction_count(address, Some(BlockNumber::Pending.into())).await
+    }
+}
+
+/// One relayer key together with the nonces it has in flight.
+pub(crate) struct Relayer {
+    client: Arc<Signer>,
+    nonces: NonceManager,
+    in_flight: Mutex<BTreeSet<U256>>,
+    // last polled balance, see `RelayerPool::watch_balances`
+    balance: Mutex<U256>,
+}
+
+impl Relayer {
+    pub(crate) async fn new(provider: &Provider<Http>, client: Arc<Signer>) -> Result<Self, ProviderError> {
+        let nonces = NonceManager::new(provider, client.address()).await?;
+        let balance = provider.get_balance(client.address(), None).await?;
+        Ok(Self {
+            client,
+            nonces,
+            in_flight: Mutex::new(BTreeSet::new()),
+            balance: Mutex::new(balance),
+        })
+    }
+
+    pub(crate) fn client(&self) -> Arc<Signer> {
+        self.client.clone()
+    }
+
+    pub(crate) fn address(&self) -> Address {
+        self.client.address()
+    }
+
+    pub(crate) fn next_nonce(&self) -> U256 {
+        // allocated under the in-flight lock, so `resync` never misses a nonce being handed out
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let nonce = self.nonces.next();
+        in_flight.insert(nonce);
+        nonce
+    }
+
+    /// Marks `nonce` as no longer in flight, once it is mined or replaced.
+    pub(crate) fn release(&self, nonce: U256) {
+        self.in_flight.lock().unwrap().remove(&nonce);
+    }
+
+    /// Takes back `nonce` after its transaction failed to broadcast, the next relay gets it.
+    pub(crate) fn give_back(&self, nonce: U256) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.remove(&nonce);
+        self.nonces.reuse(nonce);
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// Goes back to the node's pending nonce once nothing is in flight. Nonces of relays
+    /// not broadcast yet are unknown to the node, rewinding past them would hand them out twice.
+    pub(crate) async fn resync(&self, provider: &Provider<Http>) -> Result<(), ProviderError> {
+        if self.in_flight() > 0 {
+            return Ok(());
+        }
+        let pending = NonceManager::pending_nonce(provider, self.address()).await?;
+        let in_flight = self.in_flight.lock().unwrap();
+        if in_flight.is_empty() {
+            self.nonces.reset(pending);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn balance(&self) -> U256 {
+        *self.balance.lock().unwrap()
+    }
+
+    async fn refresh_balance(&self, provider: &Provider<Http>) -> Result<(), ProviderError> {
+        let balance = provider.get_balance(self.address(), None).await?;
+        *self.balance.lock().unwrap() = balance;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Relayer {
+    /// A relayer for the key `[key; 32]` that never talks to a node.
+    pub(crate) fn offline(key: u8, next_nonce: u64, balance: u64) -> Self {
+        use ethers::core::k256::ecdsa::SigningKey;
+        use ethers::core::k256::SecretKey;
+
+        let wallet = LocalWallet::from(SigningKey::from(SecretKey::from_be_bytes(&[key; 32]).unwrap()));
+        let provider = Provider::<Http>::try_from("http://localhost:8545").unwrap();
+        let address = ethers::signers::Signer::address(&wallet);
+        Self {
+            client: Arc::new(SignerMiddleware::new(provider, wallet)),
+            nonces: NonceManager {
+                address,
+                nonces: Mutex::new(Nonces {
+                    next: U256::from(next_nonce),
+                    unused: BTreeSet::new(),
+                }),
+            },
+            in_flight: Mutex::new(BTreeSet::new()),
+            balance: Mutex::new(U256::from(balance)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RelayerStrategy {
+    RoundRobin,
+    LeastPending,
+}
+
+impl FromStr for RelayerStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(RelayerStrategy::RoundRobin),
+            "least-pending" => Ok(RelayerStrategy::LeastPending),
+            _ => Err(format!("Unknown relayer strategy {s}")),
+        }
+    }
+}
+
+/// Spreads relays over several keys, skipping the ones that cannot pay for gas.
+pub(crate) struct RelayerPool {
+    relayers: Vec<Arc<Relayer>>,
+    strategy: RelayerStrategy,
+    min_balance: U256,
+    cursor: AtomicUsize,
+}
+
+impl RelayerPool {
+    pub(crate) fn new(relayers: Vec<Arc<Relayer>>, strategy: RelayerStrategy, min_balance: U256) -> Self {
+        Self {
+            relayers,
+            strategy,
+            min_balance,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    /// Keeps the cached balances of every relayer fresh.
+    pub(crate) fn watch_balances(self: &Arc<Self>, provider: Provider<Http>, poll_interval: Duration) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(poll_interval);
+            loop {
+                interval.tick().await;
+                for relayer in &pool.relayers {
+                    match relayer.refresh_balance(&provider).await {
+                        Ok(()) if relayer.balance() < pool.min_balance => warn!(
+                            "Relayer {:?} balance {} is below {}", relayer.address(), relayer.balance(), pool.min_balance
+                        ),
+                        Ok(()) => {}
+                        Err(e) => warn!("Could not fetch balance of relayer {:?}: {e}", relayer.address()),
+                    }
+                }
+            }
+        });
+    }
+
+    pub(crate) fn contains(&self, address: Address) -> bool {
+        self.relayers.iter().any(|relayer| relayer.address() == address)
+    }
+
+    pub(crate) fn health(&self) -> RelayerHealth {
+        let relayers: Vec<RelayerInfo> = self.relayers.iter()
+            .map(|relayer| RelayerInfo {
+                address: ethers::utils::to_checksum(&relayer.address(), None),
+                balance: relayer.balance().to_string(),
+                in_flight: relayer.in_flight(),
+                funded: relayer.balance() >= self.min_balance,
+            })
+            .collect();
+        RelayerHealth {
+            accepting_relays: relayers.iter().any(|relayer| relayer.funded),
+            min_balance: self.min_balance.to_string(),
+            relayers,
+        }
+    }
+
+    /// Picks the relayer for the next transaction, failing when none of them can pay for gas.
+    pub(crate) fn pick(&self) -> Result<Arc<Relayer>, SafeError> {
+        let mut candidates = self.relayers.clone();
+        match self.strategy {
+            RelayerStrategy::RoundRobin => {
+                let start = self.cursor.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            RelayerStrategy::LeastPending => candidates.sort_by_key(|relayer| relayer.in_flight()),
+        }
+
+        candidates.into_iter()
+            .find(|relayer| relayer.balance() >= self.min_balance)
+            .ok_or(SafeError::RelayerUnderfunded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonce_manager(next: u64) -> NonceManager {
+        NonceManager {
+            address: Address::zero(),
+            nonces: Mutex::new(Nonces {
+                next: U256::from(next),
+                unused: BTreeSet::new(),
+            }),
+        }
+    }
+
+    fn nonces(next: impl Fn() -> U256, count: usize) -> Vec<u64> {
+        (0..count).map(|_| next().as_u64()).collect()
+    }
+
+    #[test]
+    fn nonces_are_handed_out_in_order() {
+        let nonce_manager = nonce_manager(7);
+        assert_eq!(nonces(|| nonce_manager.next(), 3), [7, 8, 9]);
+    }
+
+    #[test]
+    fn reused_nonces_are_handed_out_lowest_first() {
+        let nonce_manager = nonce_manager(7);
+        nonces(|| nonce_manager.next(), 4);
+        nonce_manager.reuse(U256::from(9));
+        nonce_manager.reuse(U256::from(8));
+        assert_eq!(nonces(|| nonce_manager.next(), 3), [8, 9, 11]);
+    }
+
+    #[test]
+    fn reset_goes_back_to_the_pending_nonce() {
+        let nonce_manager = nonce_manager(7);
+        nonces(|| nonce_manager.next(), 3);
+        nonce_manager.reuse(U256::from(8));
+        nonce_manager.reset(U256::from(5));
+        assert_eq!(nonces(|| nonce_manager.next(), 2), [5, 6]);
+        nonce_manager.reset(U256::from(12));
+        assert_eq!(nonces(|| nonce_manager.next(), 1), [12]);
+    }
+
+    #[test]
+    fn relayers_track_nonces_in_flight() {
+        let relayer = Relayer::offline(1, 3, 0);
+        assert_eq!(nonces(|| relayer.next_nonce(), 3), [3, 4, 5]);
+        assert_eq!(relayer.in_flight(), 3);
+        relayer.release(U256::from(3));
+        assert_eq!(relayer.in_flight(), 2);
+        // releasing twice, e.g. once replaced and once mined, changes nothing
+        relayer.release(U256::from(3));
+        assert_eq!(relayer.in_flight(), 2);
+        assert_eq!(nonces(|| relayer.next_nonce(), 1), [6]);
+    }
+
+    #[test]
+    fn given_back_nonces_fill_the_gap() {
+        let relayer = Relayer::offline(1, 3, 0);
+        nonces(|| relayer.next_nonce(), 3);
+        relayer.give_back(U256::from(4));
+        assert_eq!(relayer.in_flight(), 2);
+        assert_eq!(nonces(|| relayer.next_nonce(), 2), [4, 6]);
+        assert_eq!(relayer.in_flight(), 4);
+    }
+
+    #[tokio::test]
+    async fn resync_waits_for_nonces_in_flight() {
+        let relayer = Relayer::offline(1, 3, 0);
+        nonces(|| relayer.next_nonce(), 2);
+        relayer.give_back(U256::from(3));
+        // nothing listens there, so a resync that asks the node fails
+        let provider = Provider::<Http>::try_from("http://localhost:1").unwrap();
+        assert!(relayer.resync(&provider).await.is_ok());
+        assert_eq!(nonces(|| relayer.next_nonce(), 2), [3, 5]);
+
+        for nonce in [3, 4, 5] {
+            relayer.release(U256::from(nonce));
+        }
+        assert!(relayer.resync(&provider).await.is_err());
+    }
+
+    fn pool(strategy: RelayerStrategy, relayers: &[Arc<Relayer>]) -> RelayerPool {
+        RelayerPool::new(relayers.to_vec(), strategy, U256::from(100))
+    }
+
+    fn picks(pool: &RelayerPool, count: usize) -> Vec<Address> {
+        (0..count).map(|_| pool.pick().unwrap().address()).collect()
+    }
+
+    #[test]
+    fn round_robin_skips_underfunded_relayers() {
+        let relayers = [Arc::new(Relayer::offline(1, 0, 100)), Arc::new(Relayer::offline(2, 0, 99)), Arc::new(Relayer::offline(3, 0, 1000))];
+        let pool = pool(RelayerStrategy::RoundRobin, &relayers);
+        let (first, third) = (relayers[0].address(), relayers[2].address());
+        assert_eq!(picks(&pool, 4), [first, third, third, first]);
+    }
+
+    #[test]
+    fn least_pending_picks_the_funded_relayer_with_fewest_in_flight() {
+        let relayers = [Arc::new(Relayer::offline(1, 0, 100)), Arc::new(Relayer::offline(2, 0, 0)), Arc::new(Relayer::offline(3, 0, 100))];
+        let pool = pool(RelayerStrategy::LeastPending, &relayers);
+        relayers[0].next_nonce();
+        assert_eq!(picks(&pool, 1), [relayers[2].address()]);
+        relayers[2].next_nonce();
+        relayers[2].next_nonce();
+        assert_eq!(picks(&pool, 1), [relayers[0].address()]);
+    }
+
+    #[test]
+    fn no_funded_relayer_refuses_relays() {
+        let relayers = [Arc::new(Relayer::offline(1, 0, 99)), Arc::new(Relayer::offline(2, 0, 0))];
+        for strategy in [RelayerStrategy::RoundRobin, RelayerStrategy::LeastPending] {
+            let pool = pool(strategy, &relayers);
+            assert!(matches!(pool.pick(), Err(SafeError::RelayerUnderfunded)));
+            let health = pool.health();
+            assert!(!health.accepting_relays);
+            assert!(health.relayers.iter().all(|relayer| !relayer.funded));
+        }
+    }
+
+    #[test]
+    fn relayer_strategies_parse() {
+        assert_eq!("round-robin".parse(), Ok(RelayerStrategy::RoundRobin));
+        assert_eq!("least-pending".parse(), Ok(RelayerStrategy::LeastPending));
+        assert!("random".parse::<RelayerStrategy>().is_err());
+    }
+}
@@ -1,18 +1,30 @@
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use ethers::abi::Token;
+use ethers::abi::AbiDecode;
 use ethers::contract::builders::ContractCall;
 use ethers::core::k256::SecretKey;
+use ethers::core::types::transaction::eip2718::TypedTransaction;
 use ethers::prelude::*;
 use ethers::prelude::k256::ecdsa::SigningKey;
 use ethers::providers::Provider;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use ethers::utils::{hex, keccak256};
-use log::debug;
+use log::{debug, warn};
 
-use crate::ethers_ext::solidity_keccak256;
-use crate::safe::{Safe, SafeError, SafeInfo, SafeResponse};
-use crate::safe_config::SafeConfig;
+use crate::ethers_ext::{revert_bytes, revert_data, revert_reason, solidity_keccak256};
+use crate::safe::{BatchCall, ModuleChange, OwnerChange, RelayerHealth, RelayStatus, Safe, SafeError, SafeFeeQuote, SafeInfo, SafeModules, SafeResponse, SafeSetup, SafeState, SafeTxHash, SafeTxPayload};
+use crate::safe_config::{ConfigErrors, SafeConfig};
+use crate::safe_contracts::is_multi_send_call_only;
+use crate::safe_quote::{base_gas, ECDSA_OVER_APPROVAL_GAS, GasPriceOracle, StaticPriceTable};
+use crate::safe_refund::RefundPolicy;
+use crate::safe_relayer::{Relayer, RelayerPool, RelayerStrategy};
+use crate::safe_tracker::{GasBump, RelayTracker};
+use crate::safe_multi_send::{decode_multi_send, encode_multi_send};
+use crate::safe_multicall::{decode_aggregate3, encode_aggregate3};
+use crate::safe_tx::{describe_revert, domain_has_chain_id, domain_separator, InnerCall, SafeSignature, SafeTx, SIGNATURE_LENGTH, split_signatures};
 
 // not the best idea, bruh
 #[macro_use]
@@ -38,7 +50,36 @@ pub(self) mod helper_macro {
 
 const THRESHOLD: usize = 1;
 
-type Signer = SignerMiddleware<Provider<Http>, Wallet<SigningKey>>;
+const MAX_LIST_COUNT: u64 = 100;
+
+// code lookups of a listing in flight at once, enough without flooding the node
+const LIST_CONCURRENCY: usize = 10;
+
+// legacy EIP-1271 magic value returned by `isValidSignature(bytes,bytes)`
+const EIP1271_MAGIC_VALUE: [u8; 4] = [0x20, 0xc1, 0x3b, 0x0b];
+
+// head and tail of the owners linked list inside the Safe contract
+const SENTINEL_OWNERS: Address = H160([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+]);
+
+// head and tail of the modules linked list
+const SENTINEL_MODULES: Address = H160([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+]);
+
+const MODULES_PAGE_SIZE: u64 = 50;
+
+// after singleton, modules, owners, ownerCount, threshold, nonce, domain separator and signedMessages
+const APPROVED_HASHES_SLOT: u64 = 8;
+
+// on top of what `execTransaction` needs at its estimate, see `exec_gas_limit`
+const EXEC_GAS_HEADROOM: u64 = 10_000;
+
+// where `FallbackManager` keeps the handler address
+const FALLBACK_HANDLER_STORAGE_SLOT: &str = "fallback_manager.handler.address";
+
+pub(crate) type Signer = SignerMiddleware<Provider<Http>, Wallet<SigningKey>>;
 
 abigen!(
         ProxyFactory, "./abi/proxy_factory_abi.json";
@@ -49,12 +90,35 @@ abigen!(
 pub(crate) struct SafeService {
     provider: Provider<Http>,
     client: Arc<Signer>,
+    chain_id: U256,
     fallback_addr: Address,
     master_copy_addr: Address,
-    master_copy: MasterCopy<Signer>,
     proxy_factory_addr: Address,
     proxy_factory: ProxyFactory<Signer>,
-    salt_nonce: Vec<u8>,
+    proxy_creation_code: Bytes,
+    // Safes of the master copy put the chain id into their EIP-712 domain
+    domain_has_chain_id: bool,
+    salt_nonce: U256,
+    relayers: Arc<RelayerPool>,
+    refund_policy: Arc<RefundPolicy>,
+    gas_price_oracle: Arc<dyn GasPriceOracle>,
+    quote_ttl: u64,
+    multi_send_addr: Option<Address>,
+    // MultiSendCallOnly rejects delegatecalls in a batch
+    multi_send_call_only: bool,
+    // deploying before executing needs it, to do both in one transaction
+    multicall_addr: Option<Address>,
+    tracker: RelayTracker,
+}
+
+/// On-chain state a SafeTx is hashed and checked against.
+struct SafeContext {
+    owners: Vec<Address>,
+    threshold: usize,
+    nonce: U256,
+    domain_separator: [u8; 32],
+    // counterfactual Safes have no code to ask for approved hashes yet
+    deployed: bool,
 }
 
 enum Operation {
@@ -75,69 +139,156 @@ impl TryFrom<u8> for Operation {
 }
 
 impl SafeService {
-    pub(crate) async fn new(safe_config: SafeConfig) -> Self {
-        let provider = Provider::<Http>::try_from(safe_config.rpc_url).unwrap();
-        debug!("Provider's chain id is {:?}", provider.get_chainid().await.unwrap());
+    /// Expects a configuration `SafeConfig::load` has validated.
+    /// Connects to the chain of `safe_config`, which is validated already, so only the RPC can fail.
+    pub(crate) async fn new(safe_config: SafeConfig) -> Result<Self, ConfigErrors> {
+        let rpc_url = safe_config.rpc_url.clone();
+        let rpc_err = |what: &str, e: String| ConfigErrors::from(format!("RPC_URL {rpc_url} failed to {what}: {e}"));
+        let provider = Provider::<Http>::try_from(safe_config.rpc_url.as_str())
+            .map_err(|e| rpc_err("parse", e.to_string()))?;
+        let chain_id = provider.get_chainid().await
+            .map_err(|e| rpc_err("return the chain id", e.to_string()))?;
+        debug!("Provider's chain id is {:?}", chain_id);
 
-        let secret_key = SecretKey::from_be_bytes(
-            hex::decode(safe_config.backend_private_key).unwrap().as_slice()
-        ).unwrap();
-        let signing_key = SigningKey::from(secret_key);
-        let signer = LocalWallet::from(signing_key);
+        let mut relayers = Vec::with_capacity(safe_config.backend_private_keys.len());
+        for backend_private_key in safe_config.backend_private_keys {
+            let secret_key = SecretKey::from_be_bytes(
+                hex::decode(backend_private_key).unwrap().as_slice()
+            ).unwrap();
+            let signing_key = SigningKey::from(secret_key);
+            let signer = LocalWallet::from(signing_key);
 
-        let client = SignerMiddleware::new_with_provider_chain(
-            provider.clone(),
-            signer,
-        ).await.unwrap();
+            let client = SignerMiddleware::new_with_provider_chain(
+                provider.clone(),
+                signer,
+            ).await.map_err(|e| rpc_err("return the chain id", e.to_string()))?;
 
-        let client = Arc::new(client);
+            let relayer = Relayer::new(&provider, Arc::new(client)).await
+                .map_err(|e| rpc_err("return the relayer nonce and balance", e.to_string()))?;
+            relayers.push(Arc::new(relayer));
+        }
+        // reads and call encoding go through the first relayer
+        let client = relayers[0].client();
+        let relayers = Arc::new(RelayerPool::new(
+            relayers,
+            safe_config.relayer_strategy.parse::<RelayerStrategy>().unwrap(),
+            U256::from_dec_str(&safe_config.relayer_min_balance).unwrap(),
+        ));
+        relayers.watch_balances(provider.clone(), Duration::from_secs(safe_config.balance_poll_interval));
+        let refund_policy = Arc::new(safe_config.refund_gas_tokens.parse::<RefundPolicy>().unwrap());
+        let gas_price_oracle = Arc::new(safe_config.gas_token_prices.parse::<StaticPriceTable>().unwrap());
 
         let fallback_addr = safe_config.fallback_addr.parse::<Address>().unwrap();
         let master_copy_addr = safe_config.master_copy_addr.parse::<Address>().unwrap();
         let proxy_factory_addr = safe_config.proxy_factory_addr.parse::<Address>().unwrap();
+        let multi_send_addr = safe_config.multi_send_addr.map(|addr| addr.parse::<Address>().unwrap());
+        let multicall_addr = safe_config.multicall_addr.map(|addr| addr.parse::<Address>().unwrap());
+
+        // the flag only restricts batches further, delegatecalls also need the code of a full MultiSend
+        let multi_send_call_only = match multi_send_addr {
+            Some(multi_send_addr) => safe_config.multi_send_call_only || is_multi_send_call_only(&provider, multi_send_addr).await,
+            None => true,
+        };
+        debug!("MultiSend call only: {}", multi_send_call_only);
 
         let proxy_factory = ProxyFactory::new(proxy_factory_addr, client.clone());
-        let master_copy = MasterCopy::new(master_copy_addr, client.clone());
-        let salt_nonce = hex::decode(safe_config.salt_nonce).unwrap();
+        let salt_nonce = U256::from(hex::decode(safe_config.salt_nonce).unwrap().as_slice());
+
+        // creation code never changes for a deployed factory, so a single fetch is enough
+        let proxy_creation_code = match safe_config.proxy_creation_code {
+            Some(code) => Bytes::from(hex::decode(code.trim_start_matches("0x")).unwrap()),
+            None => proxy_factory.proxy_creation_code().call().await
+                .map_err(|e| rpc_err("return the proxy creation code", e.to_string()))?,
+        };
+        debug!("Proxy creation code: {}", proxy_creation_code);
 
-        Self {
+        // Safes not deployed yet have no domain separator to read, so it is computed for their version
+        let version = MasterCopy::new(master_copy_addr, client.clone()).version().call().await
+            .map_err(|e| rpc_err("return the master copy version", e.to_string()))?;
+        debug!("Master copy version: {}", version);
+
+        let tracker = RelayTracker::new(
+            provider.clone(),
+            Duration::from_secs(safe_config.relay_poll_interval),
+            safe_config.relay_confirmations,
+            GasBump {
+                percent: safe_config.gas_bump_percent,
+                max_fee: U256::from_dec_str(&safe_config.gas_bump_max_fee).unwrap(),
+                interval: Duration::from_secs(safe_config.gas_bump_interval),
+            },
+        );
+
+        Ok(Self {
             provider,
             client,
+            chain_id,
             fallback_addr,
             master_copy_addr,
-            master_copy,
             proxy_factory_addr,
             proxy_factory,
+            proxy_creation_code,
+            domain_has_chain_id: domain_has_chain_id(&version),
             salt_nonce,
-        }
+            relayers,
+            refund_policy,
+            gas_price_oracle,
+            quote_ttl: safe_config.quote_ttl,
+            multi_send_addr,
+            multi_send_call_only,
+            multicall_addr,
+            tracker,
+        })
     }
 
-    async fn calculate_address(&self, user_address: &str) -> Result<Address, SafeError> {
-        let user_address = as_addr_err!(user_address.parse::<Address>());
-        let initializer = self.encode_initializer(user_address)?;
-        let initializer_hash = keccak256(initializer);
-        debug!("Initializer hash: {:?}", ethers::utils::hex::encode(&initializer_hash));
+    pub(crate) fn chain_id(&self) -> U256 {
+        self.chain_id
+    }
 
-        let salt = solidity_keccak256(&[
-            Token::Bytes(initializer_hash.to_vec()),
-            Token::Uint(U256::from(self.salt_nonce.as_slice())),
-        ]);
-        debug!("Salt: {:?}", ethers::utils::hex::encode(&salt));
+    fn calculate_address(&self, owners: &[Address], threshold: usize, fallback_addr: Address, salt_nonce: U256) -> Address {
+        let initializer = encode_initializer(owners, threshold, fallback_addr);
+        calculate_create2_address(
+            self.proxy_factory_addr,
+            self.master_copy_addr,
+            &self.proxy_creation_code,
+            &initializer,
+            salt_nonce,
+        )
+    }
 
-        let init_code: Bytes = as_rpc_err!(self.proxy_factory.proxy_creation_code().call().await);
-        let init_code_hash = solidity_keccak256(&[
-            Token::Bytes(init_code.to_vec()),
-            Token::Uint(U256::from(self.master_copy_addr.as_bytes())),
-        ]);
-        debug!("Init code hash: {:?}", ethers::utils::hex::encode(&init_code_hash));
+    /// Resolves and validates the owner set the Safe is set up with,
+    /// mirroring the checks `setup` performs on-chain.
+    fn owners(&self, user_address: &str, setup: &SafeSetup) -> Result<(Vec<Address>, usize), SafeError> {
+        setup_owners(as_addr_err!(user_address.parse::<Address>()), setup)
+    }
 
-        let create2_address = ethers::utils::get_create2_address_from_hash(
-            self.proxy_factory_addr,
-            salt,
-            init_code_hash,
-        );
-        debug!("Create2 address: {}", ethers::utils::to_checksum(&create2_address, None));
-        Ok(create2_address)
+    fn salt_nonce(&self, setup: &SafeSetup) -> Result<U256, SafeError> {
+        match &setup.salt_nonce {
+            Some(salt_nonce) => Ok(as_u256_err!(U256::from_dec_str(salt_nonce))),
+            None => Ok(self.salt_nonce),
+        }
+    }
+
+    fn fallback_addr(&self, setup: &SafeSetup) -> Result<Address, SafeError> {
+        match &setup.fallback_handler {
+            Some(fallback_handler) => Ok(as_addr_err!(fallback_handler.parse::<Address>())),
+            None => Ok(self.fallback_addr),
+        }
+    }
+
+    async fn safe_info(&self,
+                       owners: Vec<Address>,
+                       threshold: usize,
+                       fallback_addr: Address,
+                       salt_nonce: U256) -> Result<SafeInfo, SafeError> {
+        let address = self.calculate_address(&owners, threshold, fallback_addr, salt_nonce);
+        let is_deployed = self.is_deployed(address).await?;
+        Ok(SafeInfo {
+            address: ethers::utils::to_checksum(&address, None),
+            is_deployed,
+            owners: owners.iter().map(|owner| ethers::utils::to_checksum(owner, None)).collect(),
+            threshold,
+            salt_nonce: salt_nonce.to_string(),
+        })
     }
 
     async fn is_deployed(&self, address: Address) -> Result<bool, SafeError> {
@@ -147,100 +298,1104 @@ impl SafeService {
         Ok(!code.is_empty())
     }
 
-    fn encode_initializer(&self, user_address: Address) -> Result<Bytes, SafeError> {
-        let tokens: &[Token] = &[
-            Token::Array(vec![Token::Address(user_address)]), // owners
-            Token::Uint(U256::from(THRESHOLD)), // threshold
-            Token::Address(Address::zero()), // to
-            Token::Bytes(vec![]), // data
-            Token::Address(self.fallback_addr), // fallbackHandler
-            Token::Address(Address::zero()), // paymentToken
-            Token::Uint(U256::from(0)), // payment
-            Token::Address(Address::zero()) // paymentReceiver
-        ];
+    async fn deployed_safe(&self, user_address: &str, setup: &SafeSetup) -> Result<MasterCopy<Signer>, SafeError> {
+        let SafeInfo { address, is_deployed, .. } = self.info(user_address, setup).await?;
+        if !is_deployed {
+            return Err(SafeError::NotDeployed);
+        }
+        Ok(MasterCopy::new(as_addr_err!(address.parse::<Address>()), self.client.clone()))
+    }
+
+    /// The call a Safe transaction makes, `calls` packed into a delegatecall to MultiSend
+    /// or else the single call given by `to`, `value`, `data` and `operation`.
+    fn inner_call(&self, to: &str, value: &str, data: Vec<u8>, operation: u8, calls: &[BatchCall]) -> Result<InnerCall, SafeError> {
+        if calls.is_empty() {
+            let _ = Operation::try_from(operation)?;
+            return Ok(InnerCall {
+                to: as_addr_err!(to.parse::<Address>()),
+                value: as_u256_err!(U256::from_dec_str(value)),
+                data: Bytes::from(data),
+                operation,
+            });
+        }
+        check_no_single_call(to, value, &data, operation)?;
+        let multi_send_addr = self.multi_send_addr
+            .ok_or_else(|| SafeError::BadParams("batched calls are not supported, MultiSend is not configured".to_string()))?;
+
+        let calls = calls.iter()
+            .enumerate()
+            .map(|(i, call)| {
+                if let Operation::DelegateCall = Operation::try_from(call.operation)? {
+                    if self.multi_send_call_only {
+                        return Err(SafeError::BadParams(format!("call {i}: MultiSendCallOnly does not delegatecall")));
+                    }
+                }
+                Ok(InnerCall {
+                    to: call.to.parse::<Address>().map_err(|e| SafeError::BadAddress(format!("call {i} to {e}")))?,
+                    value: U256::from_dec_str(&call.value).map_err(|e| SafeError::BadParams(format!("call {i} value {e}")))?,
+                    data: Bytes::from(call.data.clone()),
+                    operation: call.operation,
+                })
+            })
+            .collect::<Result<Vec<_>, SafeError>>()?;
+        Ok(InnerCall {
+            to: multi_send_addr,
+            value: U256::zero(),
+            data: encode_multi_send(&calls),
+            operation: Operation::DelegateCall as u8,
+        })
+    }
+
+    /// The calls of a MultiSend transaction, unpacked from what the owners sign.
+    fn batched_calls(&self, safe_tx: &SafeTx) -> Option<Vec<BatchCall>> {
+        if self.multi_send_addr != Some(safe_tx.to) || safe_tx.operation != Operation::DelegateCall as u8 {
+            return None;
+        }
+        decode_multi_send(&safe_tx.data).ok()
+            .map(|calls| calls.iter().map(BatchCall::from).collect())
+    }
 
-        let encoded_initializer = as_rpc_err!(self.master_copy.encode("setup", tokens));
-        debug!("Encoded initializer: {:?}", ethers::utils::hex::encode(&encoded_initializer));
-        Ok(encoded_initializer)
+    #[allow(clippy::too_many_arguments)]
+    fn safe_tx(&self,
+               call: InnerCall,
+               safe_tx_gas: &str,
+               base_gas: &str,
+               gas_price: &str,
+               gas_token: &str,
+               refund_receiver: &str,
+               nonce: U256) -> Result<SafeTx, SafeError> {
+        Ok(SafeTx {
+            to: call.to,
+            value: call.value,
+            data: call.data,
+            operation: call.operation,
+            safe_tx_gas: as_u256_err!(U256::from_dec_str(safe_tx_gas)),
+            base_gas: as_u256_err!(U256::from_dec_str(base_gas)),
+            gas_price: as_u256_err!(U256::from_dec_str(gas_price)),
+            gas_token: as_addr_err!(gas_token.parse::<Address>()),
+            refund_receiver: as_addr_err!(refund_receiver.parse::<Address>()),
+            nonce,
+        })
+    }
+
+    /// Dry-runs `tx` on top of the pending block, returning its output and gas estimate.
+    async fn simulate(&self, tx: &TypedTransaction) -> Result<(Bytes, U256), SafeError> {
+        let block = Some(BlockId::Number(BlockNumber::Pending));
+        let output = self.provider.call(tx, block).await.map_err(simulation_err)?;
+        let gas = self.provider.estimate_gas(tx, block).await.map_err(simulation_err)?;
+        debug!("Simulated call to {:?}: output {}, gas {}", tx.to(), output, gas);
+        Ok((output, gas))
+    }
+
+    /// Runs `tx` with the gas limit it is sent with, unlike the estimate which gets the block gas limit.
+    async fn call_with_gas_limit(&self, tx: &TypedTransaction) -> Result<Bytes, SafeError> {
+        self.provider.call(tx, Some(BlockId::Number(BlockNumber::Pending))).await.map_err(simulation_err)
+    }
+
+    /// Gas the inner call of a Safe transaction needs, `requiredTxGas` reverts with the amount.
+    async fn required_tx_gas(&self, safe: &MasterCopy<Signer>, safe_tx: &SafeTx) -> Result<U256, SafeError> {
+        // only the Safe itself may call `requiredTxGas`
+        let contract_call: ContractCall<_, _> = safe.required_tx_gas(
+            safe_tx.to,
+            safe_tx.value,
+            safe_tx.data.clone(),
+            safe_tx.operation,
+        ).from(safe.address());
+        // Safe 1.4.x dropped `requiredTxGas`, its fallback returns nothing or reverts without the amount
+        if let Err(e) = self.provider.call(&contract_call.tx, Some(BlockId::Number(BlockNumber::Pending))).await {
+            if let Some(gas) = revert_data(&e).as_deref().and_then(revert_bytes).filter(|gas| gas.len() == 32) {
+                return Ok(U256::from_big_endian(&gas));
+            }
+        }
+        self.inner_call_estimate(safe, safe_tx).await
     }
+
+    /// Estimates the inner call of a Safe transaction as if the Safe made it. A delegatecall runs the
+    /// target's code overridden into the Safe, which is what it does in the Safe's context.
+    async fn inner_call_estimate(&self, safe: &MasterCopy<Signer>, safe_tx: &SafeTx) -> Result<U256, SafeError> {
+        let block = BlockId::Number(BlockNumber::Pending);
+        let tx: TypedTransaction = TransactionRequest::new()
+            .from(safe.address())
+            .to(if safe_tx.operation == Operation::DelegateCall as u8 { safe.address() } else { safe_tx.to })
+            .value(safe_tx.value)
+            .data(safe_tx.data.clone())
+            .into();
+        if safe_tx.operation != Operation::DelegateCall as u8 {
+            return self.provider.estimate_gas(&tx, Some(block)).await.map_err(simulation_err);
+        }
+        let code = as_rpc_err!(self.provider.get_code(safe_tx.to, Some(block)).await);
+        let overrides = serde_json::json!({ format!("{:?}", safe.address()): { "code": code } });
+        self.provider.request::<_, U256>("eth_estimateGas", (&tx, "pending", overrides)).await.map_err(simulation_err)
+    }
+
+    /// Broadcasts `tx` and hands it over to the tracker instead of waiting for the receipt.
+    async fn relay_tx(&self, relayer: Arc<Relayer>, mut tx: TypedTransaction, gas: U256) -> Result<SafeResponse, SafeError> {
+        let client = relayer.client();
+        let nonce = relayer.next_nonce();
+        tx.set_gas(gas);
+        tx.set_nonce(nonce);
+
+        let sent = match client.fill_transaction(&mut tx, None).await {
+            Ok(()) => client.send_transaction(tx.clone(), None).await.map(|pending| pending.tx_hash()),
+            Err(e) => Err(e),
+        };
+        let tx_hash = match sent {
+            Ok(tx_hash) => tx_hash,
+            Err(e) => {
+                // the allocated nonce is unused now, later relays must not leave a gap behind it
+                relayer.give_back(nonce);
+                if let Err(e) = relayer.resync(&self.provider).await {
+                    warn!("Could not resync relayer nonce: {e}");
+                }
+                return Err(SafeError::RpcError(format!("to {e}")));
+            }
+        };
+        let relay_id = self.tracker.track(relayer, tx, tx_hash);
+        debug!("Relay {relay_id} broadcast as {tx_hash:?}");
+
+        Ok(SafeResponse {
+            relay_id,
+            transaction_hash: format!("{tx_hash:?}"),
+            calls: None,
+        })
+    }
+
+    /// Every enabled module, with all pages read at `block` when given so they add up to one list.
+    async fn all_modules(&self, safe: &MasterCopy<Signer>, block: Option<BlockId>) -> Result<Vec<Address>, ContractError<Signer>> {
+        let mut modules = Vec::new();
+        let mut start = SENTINEL_MODULES;
+        loop {
+            let mut page = safe.get_modules_paginated(start, U256::from(MODULES_PAGE_SIZE));
+            if let Some(block) = block {
+                page = page.block(block);
+            }
+            let (page, next) = page.call().await?;
+            let cursor = modules_cursor(&page, next);
+            modules.extend(page);
+            match cursor {
+                Some(cursor) => start = cursor,
+                None => return Ok(modules),
+            }
+        }
+    }
+
+    /// Fills in the refund the relayer accepts for `safe_tx`, returning until when it does (unix seconds).
+    async fn quote(&self, safe: &MasterCopy<Signer>, context: &SafeContext, safe_tx: &mut SafeTx) -> Result<u64, SafeError> {
+        let min_gas_price = self.refund_policy.min_gas_price(safe_tx.gas_token)?;
+        let ether_gas_price = as_rpc_err!(self.provider.get_gas_price().await);
+        let gas_price = self.gas_price_oracle.gas_price(safe_tx.gas_token, ether_gas_price).await?;
+        let required_gas = self.required_tx_gas(safe, safe_tx).await?;
+
+        // headroom for state changing between the quote and the execution
+        safe_tx.safe_tx_gas = required_gas + required_gas / 10;
+        safe_tx.gas_price = gas_price.max(min_gas_price);
+        safe_tx.refund_receiver = self.client.address();
+        // placeholders cost at least as much calldata gas as the final values
+        safe_tx.base_gas = U256::from(u32::MAX);
+        let calldata = safe.exec_transaction(
+            safe_tx.to,
+            safe_tx.value,
+            safe_tx.data.clone(),
+            safe_tx.operation,
+            safe_tx.safe_tx_gas,
+            safe_tx.base_gas,
+            safe_tx.gas_price,
+            safe_tx.gas_token,
+            safe_tx.refund_receiver,
+            Bytes::from(vec![0xff; context.threshold * SIGNATURE_LENGTH]),
+        ).calldata().unwrap_or_default();
+        safe_tx.base_gas = base_gas(&calldata, context.threshold, safe_tx.gas_token, safe_tx.safe_tx_gas);
+        // exec checks the refund against this very estimate, the heuristic only covers nodes without overrides
+        if let Some(estimate) = self.exec_estimate(safe, context, safe_tx).await {
+            safe_tx.base_gas = safe_tx.base_gas.max(exec_gas_limit(estimate, safe_tx.safe_tx_gas).saturating_sub(safe_tx.safe_tx_gas));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Ok(now + self.quote_ttl)
+    }
+
+    /// Estimates `execTransaction` the way exec does, before the owners signed: their approvals are
+    /// overridden into `approvedHashes` instead. `None` when the node takes no state overrides.
+    async fn exec_estimate(&self, safe: &MasterCopy<Signer>, context: &SafeContext, safe_tx: &SafeTx) -> Option<U256> {
+        let tx_hash = safe_tx.hash(context.domain_separator);
+        let mut owners = context.owners.clone();
+        owners.sort();
+        owners.truncate(context.threshold);
+
+        let mut signatures = Vec::with_capacity(owners.len() * SIGNATURE_LENGTH);
+        let mut state_diff = serde_json::Map::new();
+        for owner in &owners {
+            signatures.extend_from_slice(H256::from(*owner).as_bytes());
+            signatures.extend_from_slice(&[0; 32]);
+            signatures.push(1);
+            state_diff.insert(
+                format!("{:?}", approved_hash_slot(*owner, tx_hash)),
+                serde_json::json!(format!("{:?}", H256::from_low_u64_be(1))),
+            );
+        }
+        let contract_call: ContractCall<_, _> = safe.exec_transaction(
+            safe_tx.to,
+            safe_tx.value,
+            safe_tx.data.clone(),
+            safe_tx.operation,
+            safe_tx.safe_tx_gas,
+            safe_tx.base_gas,
+            safe_tx.gas_price,
+            safe_tx.gas_token,
+            safe_tx.refund_receiver,
+            Bytes::from(signatures),
+        ).from(self.client.address());
+        let overrides = serde_json::json!({ format!("{:?}", safe.address()): { "stateDiff": state_diff } });
+
+        match self.provider.request::<_, U256>("eth_estimateGas", (&contract_call.tx, "pending", overrides)).await {
+            Ok(estimate) => Some(estimate + U256::from(ECDSA_OVER_APPROVAL_GAS * context.threshold as u64)),
+            Err(e) => {
+                debug!("Could not estimate execTransaction with overridden approvals: {e}");
+                None
+            }
+        }
+    }
+
+    /// Quotes a call of the Safe and hashes it for the owners to sign.
+    async fn payload(&self,
+                     safe: &MasterCopy<Signer>,
+                     context: &SafeContext,
+                     to: Address,
+                     data: Bytes,
+                     operation: u8,
+                     gas_token: &str) -> Result<SafeTxPayload, SafeError> {
+        let mut safe_tx = SafeTx {
+            to,
+            value: U256::zero(),
+            data,
+            operation,
+            safe_tx_gas: U256::zero(),
+            base_gas: U256::zero(),
+            gas_price: U256::zero(),
+            gas_token: as_addr_err!(gas_token.parse::<Address>()),
+            refund_receiver: Address::zero(),
+            nonce: context.nonce,
+        };
+        let expires_at = self.quote(safe, context, &mut safe_tx).await?;
+        Ok(SafeTxPayload {
+            to: ethers::utils::to_checksum(&safe_tx.to, None),
+            value: safe_tx.value.to_string(),
+            data: safe_tx.data.to_vec(),
+            operation: safe_tx.operation,
+            safe_tx_gas: safe_tx.safe_tx_gas.to_string(),
+            base_gas: safe_tx.base_gas.to_string(),
+            gas_price: safe_tx.gas_price.to_string(),
+            gas_token: ethers::utils::to_checksum(&safe_tx.gas_token, None),
+            refund_receiver: ethers::utils::to_checksum(&safe_tx.refund_receiver, None),
+            nonce: safe_tx.nonce.to_string(),
+            safe_tx_hash: format!("{:?}", safe_tx.hash(context.domain_separator)),
+            typed_data: safe_tx.typed_data(safe.address(), self.chain_id, context.domain_separator),
+            expires_at,
+            prev_owner: None,
+            prev_module: None,
+        })
+    }
+
+    async fn safe_context(&self, safe: &MasterCopy<Signer>) -> Result<SafeContext, SafeError> {
+        let owners = safe.get_owners();
+        let threshold = safe.get_threshold();
+        let nonce = safe.nonce();
+        let domain_separator = safe.domain_separator();
+        let (owners, threshold, nonce, domain_separator) = as_rpc_err!(tokio::try_join!(
+            owners.call(),
+            threshold.call(),
+            nonce.call(),
+            domain_separator.call(),
+        ));
+        Ok(SafeContext {
+            owners,
+            threshold: threshold.as_usize(),
+            nonce,
+            domain_separator,
+            deployed: true,
+        })
+    }
+
+    /// The context a Safe not deployed yet starts with, as `setup` leaves it behind.
+    fn counterfactual_context(&self, address: Address, owners: Vec<Address>, threshold: usize) -> SafeContext {
+        SafeContext {
+            owners,
+            threshold,
+            nonce: U256::zero(),
+            domain_separator: domain_separator(address, self.chain_id, self.domain_has_chain_id),
+            deployed: false,
+        }
+    }
+
+    /// Runs the same checks as `checkSignatures` so that invalid signatures
+    /// are rejected before the relayer pays for a reverted transaction.
+    async fn check_signatures(&self,
+                              relayer: Address,
+                              safe: &MasterCopy<Signer>,
+                              context: &SafeContext,
+                              safe_tx: &SafeTx,
+                              signatures: &[u8]) -> Result<(), SafeError> {
+        let tx_hash = safe_tx.hash(context.domain_separator);
+        debug!("SafeTx hash: {:?}", tx_hash);
+
+        let signatures = split_signatures(signatures, context.threshold, tx_hash)?;
+        if !context.deployed {
+            // nobody can have approved a hash on a Safe that does not exist yet
+            if let Some(i) = signatures.iter().position(|signature| matches!(signature, SafeSignature::ApprovedHash(_))) {
+                return Err(SafeError::BadSignatures(format!("signature {i}: approved hashes need a deployed Safe")));
+            }
+        }
+
+        let mut last_owner = Address::zero();
+        for (i, signature) in signatures.into_iter().enumerate() {
+            let owner = signature.owner();
+            if owner <= last_owner {
+                return Err(SafeError::BadSignatures(format!("signature {i}: signer {owner:?} is not sorted or duplicated")));
+            }
+            if !context.owners.contains(&owner) {
+                return Err(SafeError::BadSignatures(format!("signature {i}: signer {owner:?} is not an owner")));
+            }
+            match signature {
+                // the relayer sending the transaction is `msg.sender`
+                SafeSignature::ApprovedHash(_) if owner != relayer => {
+                    let approved = as_rpc_err!(safe.approved_hashes(owner, tx_hash.0).call().await);
+                    if approved.is_zero() {
+                        return Err(SafeError::BadSignatures(format!("signature {i}: hash is not approved by {owner:?}")));
+                    }
+                }
+                SafeSignature::Contract(_, data) => {
+                    if !context.deployed && as_rpc_err!(self.provider.get_code(owner, None).await).is_empty() {
+                        return Err(SafeError::BadSignatures(format!("signature {i}: contract owner {owner:?} is not deployed")));
+                    }
+                    let validator = MasterCopy::new(owner, self.client.clone());
+                    let magic_value = validator.is_valid_signature(
+                        Bytes::from(safe_tx.encode_data(context.domain_separator)),
+                        data,
+                    ).call().await;
+                    if !matches!(magic_value, Ok(EIP1271_MAGIC_VALUE)) {
+                        return Err(SafeError::BadSignatures(format!("signature {i}: contract {owner:?} rejected signature")));
+                    }
+                }
+                _ => {}
+            }
+            last_owner = owner;
+        }
+        Ok(())
+    }
+}
+
+fn simulation_err(e: ProviderError) -> SafeError {
+    match revert_reason(&e) {
+        Some(reason) => SafeError::ExecutionReverted(describe_revert(&reason)),
+        None => SafeError::RpcError(format!("to {e}")),
+    }
+}
+
+/// Storage slot of `approvedHashes[owner][tx_hash]`, the mapping sits at slot 8 on every Safe version.
+fn approved_hash_slot(owner: Address, tx_hash: H256) -> H256 {
+    let owner_slot = keccak256(ethers::abi::encode(&[Token::Address(owner), Token::Uint(U256::from(APPROVED_HASHES_SLOT))]));
+    H256::from(keccak256(ethers::abi::encode(&[Token::FixedBytes(tx_hash.as_bytes().to_vec()), Token::FixedBytes(owner_slot.to_vec())])))
+}
+
+/// Owners and threshold of the Safe `setup` describes for `user_address`, with the checks `setupOwners` makes.
+/// The user has to be an owner, quotas and salts are theirs and must not be spent on others' Safes.
+fn setup_owners(user_address: Address, setup: &SafeSetup) -> Result<(Vec<Address>, usize), SafeError> {
+    let owners = if setup.owners.is_empty() {
+        vec![user_address]
+    } else {
+        setup.owners.iter()
+            .map(|owner| owner.parse::<Address>().map_err(|e| SafeError::BadAddress(format!("owner {owner}: {e}"))))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let threshold = setup.threshold.unwrap_or(THRESHOLD);
+
+    if threshold == 0 {
+        return Err(SafeError::BadParams("threshold must be greater than 0".to_string()));
+    }
+    if threshold > owners.len() {
+        return Err(SafeError::BadParams(format!("threshold {threshold} exceeds {} owners", owners.len())));
+    }
+    for (i, owner) in owners.iter().enumerate() {
+        if owner.is_zero() || *owner == SENTINEL_OWNERS {
+            return Err(SafeError::BadAddress(format!("owner {owner:?} is not allowed")));
+        }
+        if owners[..i].contains(owner) {
+            return Err(SafeError::BadAddress(format!("duplicate owner {owner:?}")));
+        }
+    }
+    if !owners.contains(&user_address) {
+        return Err(SafeError::BadParams(format!("{user_address:?} is not one of the owners")));
+    }
+    Ok((owners, threshold))
+}
+
+/// The entry pointing to `entry` in one of the Safe's linked lists, given in list order.
+fn prev_entry(entries: &[Address], entry: Address) -> Option<Address> {
+    // owners and modules lists both start at the same sentinel
+    match entries.iter().position(|e| *e == entry) {
+        Some(0) => Some(SENTINEL_OWNERS),
+        Some(i) => Some(entries[i - 1]),
+        None => None,
+    }
+}
+
+fn prev_owner(owners: &[Address], owner: Address) -> Result<Address, SafeError> {
+    prev_entry(owners, owner).ok_or_else(|| SafeError::BadParams(format!("{owner:?} is not an owner")))
+}
+
+/// Where the page after a `getModulesPaginated` page starts, `None` after the last one.
+fn modules_cursor(page: &[Address], next: Address) -> Option<Address> {
+    if next == SENTINEL_MODULES || next.is_zero() {
+        return None;
+    }
+    // before 1.4.0 `next` is the first module left out of the page, later the last one in it,
+    // so only the last listed module continues the list on every version
+    page.last().copied()
+}
+
+/// Mirrors the checks `addOwnerWithThreshold` and `swapOwner` make on the new owner.
+fn check_new_owner(safe: Address, owners: &[Address], owner: Address) -> Result<(), SafeError> {
+    if owner.is_zero() || owner == SENTINEL_OWNERS || owner == safe {
+        return Err(SafeError::BadAddress(format!("owner {owner:?} is not allowed")));
+    }
+    if owners.contains(&owner) {
+        return Err(SafeError::BadParams(format!("{owner:?} is already an owner")));
+    }
+    Ok(())
+}
+
+fn check_threshold(threshold: usize, owner_count: usize) -> Result<(), SafeError> {
+    if threshold == 0 {
+        return Err(SafeError::BadParams("threshold must be greater than 0".to_string()));
+    }
+    if threshold > owner_count {
+        return Err(SafeError::BadParams(format!("threshold {threshold} exceeds {owner_count} owners")));
+    }
+    Ok(())
+}
+
+/// Checks a batch comes without any of the single call, which the MultiSend call would silently drop.
+fn check_no_single_call(to: &str, value: &str, data: &[u8], operation: u8) -> Result<(), SafeError> {
+    let value = as_u256_err!(U256::from_dec_str(value));
+    if !to.is_empty() || !value.is_zero() || !data.is_empty() || operation != Operation::Call as u8 {
+        return Err(SafeError::BadParams("either calls or a single to, value, data and operation are expected".to_string()));
+    }
+    Ok(())
+}
+
+/// Gas limit to send an `execTransaction` estimated at `estimate` with. The estimate's binary search can
+/// stop where `execTransaction` itself succeeds, but forwards too little of the 63/64 to the inner call,
+/// which then fails with the refund still paid.
+fn exec_gas_limit(estimate: U256, safe_tx_gas: U256) -> U256 {
+    estimate * 64 / 63 + safe_tx_gas / 63 + EXEC_GAS_HEADROOM
+}
+
+/// Checks the `execTransaction` output is `true`, `false` being an `ExecutionFailure`.
+fn exec_succeeded(output: Bytes) -> Result<(), SafeError> {
+    if !as_rpc_err!(bool::decode(output)) {
+        return Err(SafeError::ExecutionReverted(
+            "ExecutionFailure: Safe transaction failed, only the refund would be paid".to_string()
+        ));
+    }
+    Ok(())
+}
+
+/// Encodes the `setup` call the proxy is initialized with.
+pub(crate) fn encode_initializer(owners: &[Address], threshold: usize, fallback_addr: Address) -> Bytes {
+    let tokens: &[Token] = &[
+        Token::Array(owners.iter().copied().map(Token::Address).collect()), // owners
+        Token::Uint(U256::from(threshold)), // threshold
+        Token::Address(Address::zero()), // to
+        Token::Bytes(vec![]), // data
+        Token::Address(fallback_addr), // fallbackHandler
+        Token::Address(Address::zero()), // paymentToken
+        Token::Uint(U256::from(0)), // payment
+        Token::Address(Address::zero()) // paymentReceiver
+    ];
+
+    let encoded_initializer = [SetupCall::selector().as_slice(), &ethers::abi::encode(tokens)].concat();
+    debug!("Encoded initializer: {:?}", ethers::utils::hex::encode(&encoded_initializer));
+    Bytes::from(encoded_initializer)
+}
+
+/// Computes the address `createProxyWithNonce` deploys the proxy to,
+/// without touching the chain.
+pub(crate) fn calculate_create2_address(proxy_factory_addr: Address,
+                                        master_copy_addr: Address,
+                                        proxy_creation_code: &[u8],
+                                        initializer: &[u8],
+                                        salt_nonce: U256) -> Address {
+    let initializer_hash = keccak256(initializer);
+    debug!("Initializer hash: {:?}", ethers::utils::hex::encode(initializer_hash));
+
+    let salt = solidity_keccak256(&[
+        Token::Bytes(initializer_hash.to_vec()),
+        Token::Uint(salt_nonce),
+    ]);
+    debug!("Salt: {:?}", ethers::utils::hex::encode(salt));
+
+    let init_code_hash = solidity_keccak256(&[
+        Token::Bytes(proxy_creation_code.to_vec()),
+        Token::Uint(U256::from(master_copy_addr.as_bytes())),
+    ]);
+    debug!("Init code hash: {:?}", ethers::utils::hex::encode(init_code_hash));
+
+    let create2_address = ethers::utils::get_create2_address_from_hash(
+        proxy_factory_addr,
+        salt,
+        init_code_hash,
+    );
+    debug!("Create2 address: {}", ethers::utils::to_checksum(&create2_address, None));
+    create2_address
 }
 
 #[async_trait]
 impl Safe for SafeService {
-    async fn info(&self, user_address: &str) -> Result<SafeInfo, SafeError> {
-        let address = self.calculate_address(user_address).await?;
-        let is_deployed = self.is_deployed(address).await?;
-        let address = ethers::utils::to_checksum(&address, None);
-        Ok(SafeInfo {
-            address,
-            is_deployed,
+    async fn info(&self, user_address: &str, setup: &SafeSetup) -> Result<SafeInfo, SafeError> {
+        let (owners, threshold) = self.owners(user_address, setup)?;
+        let fallback_addr = self.fallback_addr(setup)?;
+        let salt_nonce = self.salt_nonce(setup)?;
+        self.safe_info(owners, threshold, fallback_addr, salt_nonce).await
+    }
+
+    async fn list(&self, user_address: &str, setup: &SafeSetup, from: u64, count: u64) -> Result<Vec<SafeInfo>, SafeError> {
+        if count > MAX_LIST_COUNT {
+            return Err(SafeError::BadParams(format!("count must not exceed {MAX_LIST_COUNT}")));
+        }
+        let (owners, threshold) = self.owners(user_address, setup)?;
+        let fallback_addr = self.fallback_addr(setup)?;
+        stream::iter(from..from.saturating_add(count))
+            .map(|salt_nonce| self.safe_info(owners.clone(), threshold, fallback_addr, U256::from(salt_nonce)))
+            .buffered(LIST_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    async fn state(&self, user_address: &str, setup: &SafeSetup) -> Result<SafeState, SafeError> {
+        let safe = self.deployed_safe(user_address, setup).await?;
+        let address = safe.address();
+        // every read is pinned to the same block, so the state never mixes two of them
+        let block = BlockId::from(as_rpc_err!(self.provider.get_block_number().await));
+        let owners = safe.get_owners().block(block);
+        let threshold = safe.get_threshold().block(block);
+        let nonce = safe.nonce().block(block);
+        let version = safe.version().block(block);
+        let fallback_handler_slot = H256::from(keccak256(FALLBACK_HANDLER_STORAGE_SLOT));
+        let (contract_reads, chain_reads) = tokio::join!(
+            async {
+                tokio::try_join!(
+                    owners.call(),
+                    threshold.call(),
+                    nonce.call(),
+                    version.call(),
+                    self.all_modules(&safe, Some(block)),
+                )
+            },
+            async {
+                tokio::try_join!(
+                    self.provider.get_storage_at(address, fallback_handler_slot, Some(block)),
+                    self.provider.get_balance(address, Some(block)),
+                )
+            },
+        );
+        let (owners, threshold, nonce, version, modules) = as_rpc_err!(contract_reads);
+        let (fallback_handler, balance) = as_rpc_err!(chain_reads);
+        Ok(SafeState {
+            address: ethers::utils::to_checksum(&address, None),
+            owners: owners.iter().map(|owner| ethers::utils::to_checksum(owner, None)).collect(),
+            threshold: threshold.as_usize(),
+            nonce: nonce.to_string(),
+            modules: modules.iter().map(|module| ethers::utils::to_checksum(module, None)).collect(),
+            version,
+            fallback_handler: ethers::utils::to_checksum(&Address::from(fallback_handler), None),
+            balance: balance.to_string(),
         })
     }
 
-    async fn deploy(&self, user_address: &str) -> Result<SafeResponse, SafeError> {
-        if self.info(user_address).await?.is_deployed {
+    async fn modules(&self, user_address: &str, setup: &SafeSetup, start: Option<&str>, count: u64) -> Result<SafeModules, SafeError> {
+        if count == 0 || count > MAX_LIST_COUNT {
+            return Err(SafeError::BadParams(format!("count must be between 1 and {MAX_LIST_COUNT}")));
+        }
+        let start = match start {
+            Some(start) => as_addr_err!(start.parse::<Address>()),
+            None => SENTINEL_MODULES,
+        };
+        let safe = self.deployed_safe(user_address, setup).await?;
+        let (page, next) = as_rpc_err!(safe.get_modules_paginated(start, U256::from(count)).call().await);
+        let next = modules_cursor(&page, next);
+        Ok(SafeModules {
+            modules: page.iter().map(|module| ethers::utils::to_checksum(module, None)).collect(),
+            next: next.map(|next| ethers::utils::to_checksum(&next, None)),
+        })
+    }
+
+    async fn deploy(&self, user_address: &str, setup: &SafeSetup) -> Result<SafeResponse, SafeError> {
+        if self.info(user_address, setup).await?.is_deployed {
             return Err(SafeError::AlreadyExists);
         }
-        let user_address = user_address.parse::<Address>().unwrap();
+        let (owners, threshold) = self.owners(user_address, setup)?;
+        let relayer = self.relayers.pick()?;
 
-        let mut receipt: TransactionReceipt = as_rpc_err!(as_rpc_err!(self.proxy_factory.create_proxy_with_nonce(
+        let contract_call: ContractCall<_, _> = self.proxy_factory.create_proxy_with_nonce(
             self.master_copy_addr,
-            self.encode_initializer(user_address)?,
-            U256::from(self.salt_nonce.as_slice()),
-        ).send().await).await).unwrap();
+            encode_initializer(&owners, threshold, self.fallback_addr(setup)?),
+            self.salt_nonce(setup)?,
+        ).from(relayer.address());
+        let (_, gas) = self.simulate(&contract_call.tx).await?;
 
-        let Log { block_hash, transaction_hash, .. } = receipt.logs.pop().unwrap();
-        debug!("Receipt of deployment: {:?}", receipt);
+        self.relay_tx(relayer, contract_call.tx, gas).await
+    }
 
-        Ok(SafeResponse {
-            block_hash: format!("0x{}", hex::encode(block_hash.unwrap())),
-            transaction_hash: format!("0x{}", hex::encode(transaction_hash.unwrap())),
+    #[allow(clippy::too_many_arguments)]
+    async fn tx_hash(&self,
+                     user_address: &str,
+                     setup: &SafeSetup,
+                     to: &str,
+                     value: &str,
+                     data: Vec<u8>,
+                     operation: u8,
+                     calls: &[BatchCall],
+                     safe_tx_gas: &str,
+                     base_gas: &str,
+                     gas_price: &str,
+                     gas_token: &str,
+                     refund_receiver: &str) -> Result<SafeTxHash, SafeError> {
+        let SafeInfo { address, is_deployed, .. } = self.info(user_address, setup).await?;
+        let safe = MasterCopy::new(as_addr_err!(address.parse::<Address>()), self.client.clone());
+        // the first transaction of a Safe deployed along with it is signed before the deployment
+        let context = if is_deployed {
+            self.safe_context(&safe).await?
+        } else {
+            let (owners, threshold) = self.owners(user_address, setup)?;
+            self.counterfactual_context(safe.address(), owners, threshold)
+        };
+        let safe_tx = self.safe_tx(
+            self.inner_call(to, value, data, operation, calls)?,
+            safe_tx_gas,
+            base_gas,
+            gas_price,
+            gas_token,
+            refund_receiver,
+            context.nonce,
+        )?;
+
+        Ok(SafeTxHash {
+            safe_tx_hash: format!("{:?}", safe_tx.hash(context.domain_separator)),
+            typed_data: safe_tx.typed_data(safe.address(), self.chain_id, context.domain_separator),
+            nonce: context.nonce.to_string(),
+            calls: self.batched_calls(&safe_tx),
         })
     }
 
     #[allow(clippy::too_many_arguments)]
     async fn exec(&self,
                   user_address: &str,
+                  setup: &SafeSetup,
                   to: &str,
                   value: &str,
                   data: Vec<u8>,
                   operation: u8,
+                  calls: &[BatchCall],
                   safe_tx_gas: &str,
                   base_gas: &str,
                   gas_price: &str,
                   gas_token: &str,
                   refund_receiver: &str,
                   signatures: Vec<u8>) -> Result<SafeResponse, SafeError> {
-        let SafeInfo { address, is_deployed } = self.info(user_address).await?;
-        if !is_deployed {
-            return Err(SafeError::NotDeployed);
+        let safe = self.deployed_safe(user_address, setup).await?;
+        let context = self.safe_context(&safe).await?;
+        let safe_tx = self.safe_tx(
+            self.inner_call(to, value, data, operation, calls)?,
+            safe_tx_gas,
+            base_gas,
+            gas_price,
+            gas_token,
+            refund_receiver,
+            context.nonce,
+        )?;
+        let network_gas_price = as_rpc_err!(self.provider.get_gas_price().await);
+        self.refund_policy.check_payment(&safe_tx, &self.relayers, network_gas_price)?;
+        let relayer = self.relayers.pick()?;
+        self.check_signatures(relayer.address(), &safe, &context, &safe_tx, &signatures).await?;
+
+        let contract_call: ContractCall<_, _> = safe.exec_transaction(
+            safe_tx.to,
+            safe_tx.value,
+            safe_tx.data.clone(),
+            safe_tx.operation,
+            safe_tx.safe_tx_gas,
+            safe_tx.base_gas,
+            safe_tx.gas_price,
+            safe_tx.gas_token,
+            safe_tx.refund_receiver,
+            Bytes::from(signatures),
+        ).from(relayer.address());
+
+        let (output, estimate) = self.simulate(&contract_call.tx).await?;
+        exec_succeeded(output)?;
+        let gas = exec_gas_limit(estimate, safe_tx.safe_tx_gas);
+        self.refund_policy.check_gas(&safe_tx, gas)?;
+        let mut tx = contract_call.tx;
+        tx.set_gas(gas);
+        exec_succeeded(self.call_with_gas_limit(&tx).await?)?;
+
+        let mut response = self.relay_tx(relayer, tx, gas).await?;
+        response.calls = self.batched_calls(&safe_tx);
+        Ok(response)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn deploy_exec(&self,
+                         user_address: &str,
+                         setup: &SafeSetup,
+                         to: &str,
+                         value: &str,
+                         data: Vec<u8>,
+                         operation: u8,
+                         calls: &[BatchCall],
+                         safe_tx_gas: &str,
+                         base_gas: &str,
+                         gas_price: &str,
+                         gas_token: &str,
+                         refund_receiver: &str,
+                         signatures: Vec<u8>) -> Result<SafeResponse, SafeError> {
+        // sent one after the other, the execution could neither be simulated nor undo the deployment
+        let multicall_addr = self.multicall_addr
+            .ok_or_else(|| SafeError::BadParams("deploying before executing needs a configured multicall contract".to_string()))?;
+        let (owners, threshold) = self.owners(user_address, setup)?;
+        let fallback_addr = self.fallback_addr(setup)?;
+        let salt_nonce = self.salt_nonce(setup)?;
+        let address = self.calculate_address(&owners, threshold, fallback_addr, salt_nonce);
+        if self.is_deployed(address).await? {
+            return Err(SafeError::AlreadyExists);
         }
+        let safe = MasterCopy::new(address, self.client.clone());
+        let context = self.counterfactual_context(address, owners.clone(), threshold);
+        let safe_tx = self.safe_tx(
+            self.inner_call(to, value, data, operation, calls)?,
+            safe_tx_gas,
+            base_gas,
+            gas_price,
+            gas_token,
+            refund_receiver,
+            context.nonce,
+        )?;
+        let network_gas_price = as_rpc_err!(self.provider.get_gas_price().await);
+        self.refund_policy.check_payment(&safe_tx, &self.relayers, network_gas_price)?;
+        let relayer = self.relayers.pick()?;
 
-        let _ = Operation::try_from(operation)?;
+        let deploy_call: ContractCall<_, _> = self.proxy_factory.create_proxy_with_nonce(
+            self.master_copy_addr,
+            encode_initializer(&owners, threshold, fallback_addr),
+            salt_nonce,
+        ).from(relayer.address());
+        let (_, deploy_gas) = self.simulate(&deploy_call.tx).await?;
+        let exec_call: ContractCall<_, bool> = safe.exec_transaction(
+            safe_tx.to,
+            safe_tx.value,
+            safe_tx.data.clone(),
+            safe_tx.operation,
+            safe_tx.safe_tx_gas,
+            safe_tx.base_gas,
+            safe_tx.gas_price,
+            safe_tx.gas_token,
+            safe_tx.refund_receiver,
+            Bytes::from(signatures.clone()),
+        ).from(relayer.address());
 
-        let master_copy = MasterCopy::new(as_addr_err!(address.parse::<Address>()), self.client.clone());
+        // the multicall contract is `msg.sender` of the execution
+        self.check_signatures(multicall_addr, &safe, &context, &safe_tx, &signatures).await?;
+        let mut tx = exec_call.tx.clone();
+        tx.set_to(multicall_addr);
+        tx.set_data(encode_aggregate3(&[
+            (self.proxy_factory_addr, deploy_call.calldata().unwrap_or_default()),
+            (address, exec_call.calldata().unwrap_or_default()),
+        ]));
 
-        let contract_call: ContractCall<_, _> = master_copy.exec_transaction(
-            as_addr_err!(to.parse::<Address>()),
-            as_u256_err!(U256::from_dec_str(value)),
-            Bytes::from(data),
-            operation,
-            as_u256_err!(U256::from_dec_str(safe_tx_gas)),
-            as_u256_err!(U256::from_dec_str(base_gas)),
-            as_u256_err!(U256::from_dec_str(gas_price)),
-            as_addr_err!(gas_token.parse::<Address>()),
-            as_addr_err!(refund_receiver.parse::<Address>()),
-            Bytes::from(signatures),
-        );
+        let exec_output = |output: Bytes| match decode_aggregate3(&output)?.pop() {
+            Some(exec_output) => Ok(exec_output),
+            None => Err(SafeError::RpcError("aggregate3 returned no results".to_string())),
+        };
+        let (output, estimate) = self.simulate(&tx).await?;
+        exec_succeeded(exec_output(output)?)?;
+        let gas = exec_gas_limit(estimate, safe_tx.safe_tx_gas);
+        // the deployment is sponsored, the Safe refunds its execution only
+        self.refund_policy.check_gas(&safe_tx, gas.saturating_sub(deploy_gas))?;
+        tx.set_gas(gas);
+        exec_succeeded(exec_output(self.call_with_gas_limit(&tx).await?)?)?;
 
-        let mut receipt: TransactionReceipt = as_rpc_err!(as_rpc_err!(contract_call.send().await).await).unwrap();
+        // one transaction carries both, so an error means neither was broadcast
+        let mut response = self.relay_tx(relayer, tx, gas).await?;
+        response.calls = self.batched_calls(&safe_tx);
+        Ok(response)
+    }
 
-        let Log { block_hash, transaction_hash, .. } = receipt.logs.pop().unwrap();
-        debug!("Receipt of exec_transaction: {:?}", receipt);
+    #[allow(clippy::too_many_arguments)]
+    async fn estimate(&self,
+                      user_address: &str,
+                      setup: &SafeSetup,
+                      to: &str,
+                      value: &str,
+                      data: Vec<u8>,
+                      operation: u8,
+                      calls: &[BatchCall],
+                      gas_token: &str) -> Result<SafeFeeQuote, SafeError> {
+        let safe = self.deployed_safe(user_address, setup).await?;
+        let context = self.safe_context(&safe).await?;
+        let mut safe_tx = self.safe_tx(
+            self.inner_call(to, value, data, operation, calls)?,
+            "0",
+            "0",
+            "0",
+            gas_token,
+            &format!("{:?}", Address::zero()),
+            context.nonce,
+        )?;
 
-        Ok(SafeResponse {
-            block_hash: format!("0x{}", hex::encode(block_hash.unwrap())),
-            transaction_hash: format!("0x{}", hex::encode(transaction_hash.unwrap())),
+        let expires_at = self.quote(&safe, &context, &mut safe_tx).await?;
+        Ok(SafeFeeQuote {
+            safe_tx_gas: safe_tx.safe_tx_gas.to_string(),
+            base_gas: safe_tx.base_gas.to_string(),
+            gas_price: safe_tx.gas_price.to_string(),
+            gas_token: ethers::utils::to_checksum(&safe_tx.gas_token, None),
+            refund_receiver: ethers::utils::to_checksum(&safe_tx.refund_receiver, None),
+            expires_at,
         })
     }
+
+    async fn owner_tx(&self,
+                      user_address: &str,
+                      setup: &SafeSetup,
+                      change: &OwnerChange,
+                      gas_token: &str) -> Result<SafeTxPayload, SafeError> {
+        let safe = self.deployed_safe(user_address, setup).await?;
+        let context = self.safe_context(&safe).await?;
+        let owners = &context.owners;
+        let owner_count = owners.len();
+        let parse_owner = |owner: &str| owner.parse::<Address>()
+            .map_err(|e| SafeError::BadAddress(format!("owner {owner}: {e}")));
+
+        let (contract_call, prev_owner): (ContractCall<_, ()>, _) = match change {
+            OwnerChange::Add { owner, threshold } => {
+                let owner = parse_owner(owner)?;
+                check_new_owner(safe.address(), owners, owner)?;
+                let threshold = threshold.unwrap_or(context.threshold);
+                check_threshold(threshold, owner_count + 1)?;
+                (safe.add_owner_with_threshold(owner, U256::from(threshold)), None)
+            }
+            OwnerChange::Remove { owner, threshold } => {
+                let owner = parse_owner(owner)?;
+                let prev_owner = prev_owner(owners, owner)?;
+                let threshold = threshold.unwrap_or_else(|| context.threshold.min(owner_count - 1));
+                check_threshold(threshold, owner_count - 1)?;
+                (safe.remove_owner(prev_owner, owner, U256::from(threshold)), Some(prev_owner))
+            }
+            OwnerChange::Swap { old_owner, new_owner } => {
+                let old_owner = parse_owner(old_owner)?;
+                let new_owner = parse_owner(new_owner)?;
+                let prev_owner = prev_owner(owners, old_owner)?;
+                check_new_owner(safe.address(), owners, new_owner)?;
+                (safe.swap_owner(prev_owner, old_owner, new_owner), Some(prev_owner))
+            }
+            OwnerChange::ChangeThreshold { threshold } => {
+                check_threshold(*threshold, owner_count)?;
+                (safe.change_threshold(U256::from(*threshold)), None)
+            }
+        };
+        // owner management is `authorized`, so only the Safe calling itself passes
+        let data = contract_call.calldata().unwrap_or_default();
+        let mut payload = self.payload(&safe, &context, safe.address(), data, Operation::Call as u8, gas_token).await?;
+        payload.prev_owner = prev_owner.map(|prev_owner| ethers::utils::to_checksum(&prev_owner, None));
+        Ok(payload)
+    }
+
+    async fn module_tx(&self,
+                       user_address: &str,
+                       setup: &SafeSetup,
+                       change: &ModuleChange,
+                       gas_token: &str) -> Result<SafeTxPayload, SafeError> {
+        let safe = self.deployed_safe(user_address, setup).await?;
+        let context = self.safe_context(&safe).await?;
+        let modules = as_rpc_err!(self.all_modules(&safe, None).await);
+
+        let (contract_call, prev_module): (ContractCall<_, ()>, _) = match change {
+            ModuleChange::Enable { module } => {
+                let module = as_addr_err!(module.parse::<Address>());
+                if module.is_zero() || module == SENTINEL_MODULES {
+                    return Err(SafeError::BadAddress(format!("module {module:?} is not allowed")));
+                }
+                if modules.contains(&module) {
+                    return Err(SafeError::BadParams(format!("module {module:?} is already enabled")));
+                }
+                (safe.enable_module(module), None)
+            }
+            ModuleChange::Disable { module } => {
+                let module = as_addr_err!(module.parse::<Address>());
+                let prev_module = prev_entry(&modules, module)
+                    .ok_or_else(|| SafeError::BadParams(format!("module {module:?} is not enabled")))?;
+                (safe.disable_module(prev_module, module), Some(prev_module))
+            }
+        };
+        // module management is `authorized` too
+        let data = contract_call.calldata().unwrap_or_default();
+        let mut payload = self.payload(&safe, &context, safe.address(), data, Operation::Call as u8, gas_token).await?;
+        payload.prev_module = prev_module.map(|prev_module| ethers::utils::to_checksum(&prev_module, None));
+        Ok(payload)
+    }
+
+    async fn relay(&self, relay_id: &str) -> Result<RelayStatus, SafeError> {
+        self.tracker.status(relay_id)
+            .ok_or_else(|| SafeError::UnknownRelay(relay_id.to_string()))
+    }
+
+    async fn health(&self) -> Result<RelayerHealth, SafeError> {
+        Ok(self.relayers.health())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::encode;
+
+    use super::*;
+
+    fn address(byte: u8) -> Address {
+        Address::repeat_byte(byte)
+    }
+
+    #[test]
+    fn prev_entry_follows_the_linked_list() {
+        let owners = [address(1), address(2), address(3)];
+
+        assert_eq!(prev_entry(&owners, address(1)), Some(SENTINEL_OWNERS));
+        assert_eq!(prev_entry(&owners, address(2)), Some(address(1)));
+        assert_eq!(prev_entry(&owners, address(3)), Some(address(2)));
+        assert_eq!(prev_entry(&owners, address(4)), None);
+    }
+
+    #[test]
+    fn prev_owner_refuses_non_owners() {
+        let owners = [address(1), address(2)];
+
+        assert_eq!(prev_owner(&owners, address(2)).unwrap(), address(1));
+        assert!(matches!(prev_owner(&owners, address(4)), Err(SafeError::BadParams(_))));
+    }
+
+    fn setup(owners: &[Address], threshold: Option<usize>) -> SafeSetup {
+        SafeSetup {
+            owners: owners.iter().map(|owner| format!("{owner:?}")).collect(),
+            threshold,
+            salt_nonce: None,
+            fallback_handler: None,
+        }
+    }
+
+    #[test]
+    fn setup_defaults_to_the_user_alone() {
+        assert_eq!(setup_owners(address(1), &setup(&[], None)).unwrap(), (vec![address(1)], 1));
+        assert_eq!(setup_owners(address(1), &setup(&[address(2), address(1)], Some(2))).unwrap(), (vec![address(2), address(1)], 2));
+    }
+
+    #[test]
+    fn setup_needs_the_user_among_the_owners() {
+        assert!(matches!(setup_owners(address(1), &setup(&[address(2), address(3)], Some(1))), Err(SafeError::BadParams(_))));
+    }
+
+    #[test]
+    fn setup_refuses_bad_owners() {
+        for owners in [vec![address(1), address(1)], vec![address(1), Address::zero()], vec![address(1), SENTINEL_OWNERS]] {
+            assert!(matches!(setup_owners(address(1), &setup(&owners, Some(1))), Err(SafeError::BadAddress(_))));
+        }
+        let mut unparsable = setup(&[address(1)], Some(1));
+        unparsable.owners.push("0x12".to_string());
+        assert!(matches!(setup_owners(address(1), &unparsable), Err(SafeError::BadAddress(_))));
+    }
+
+    #[test]
+    fn setup_refuses_bad_thresholds() {
+        assert!(matches!(setup_owners(address(1), &setup(&[address(1), address(2)], Some(0))), Err(SafeError::BadParams(_))));
+        assert!(matches!(setup_owners(address(1), &setup(&[address(1), address(2)], Some(3))), Err(SafeError::BadParams(_))));
+    }
+
+    #[test]
+    fn new_owners_and_thresholds_are_checked_like_the_safe_does() {
+        let (safe, owners) = (address(9), [address(1), address(2)]);
+
+        assert!(check_new_owner(safe, &owners, address(3)).is_ok());
+        for owner in [Address::zero(), SENTINEL_OWNERS, safe] {
+            assert!(matches!(check_new_owner(safe, &owners, owner), Err(SafeError::BadAddress(_))));
+        }
+        assert!(matches!(check_new_owner(safe, &owners, address(1)), Err(SafeError::BadParams(_))));
+
+        assert!(check_threshold(2, 2).is_ok());
+        assert!(check_threshold(0, 2).is_err());
+        assert!(check_threshold(3, 2).is_err());
+    }
+
+    #[test]
+    fn modules_cursor_continues_after_the_last_listed_module() {
+        let page = [address(1), address(2)];
+
+        // before 1.4.0 `next` is the first module left out
+        assert_eq!(modules_cursor(&page, address(3)), Some(address(2)));
+        // from 1.4.0 on it is the last module in the page
+        assert_eq!(modules_cursor(&page, address(2)), Some(address(2)));
+        assert_eq!(modules_cursor(&page, SENTINEL_MODULES), None);
+        assert_eq!(modules_cursor(&[], Address::zero()), None);
+    }
+
+    #[test]
+    fn batches_come_without_a_single_call() {
+        assert!(check_no_single_call("", "0", &[], 0).is_ok());
+        assert!(matches!(check_no_single_call("0x1111111111111111111111111111111111111111", "0", &[], 0), Err(SafeError::BadParams(_))));
+        assert!(matches!(check_no_single_call("", "1", &[], 0), Err(SafeError::BadParams(_))));
+        assert!(matches!(check_no_single_call("", "0", &[0x01], 0), Err(SafeError::BadParams(_))));
+        assert!(matches!(check_no_single_call("", "0", &[], 1), Err(SafeError::BadParams(_))));
+    }
+
+    #[test]
+    fn exec_gas_limit_leaves_the_inner_call_its_safe_tx_gas() {
+        let estimate = U256::from(126_000);
+        let safe_tx_gas = U256::from(63_000);
+        let gas = exec_gas_limit(estimate, safe_tx_gas);
+
+        assert_eq!(gas, U256::from(128_000 + 1_000 + EXEC_GAS_HEADROOM));
+        // whatever the estimate fell short of the 64/63 rule is covered
+        assert!(gas - estimate >= estimate / 63 + safe_tx_gas / 63);
+    }
+
+    #[test]
+    fn initializer_calls_setup() {
+        let initializer = encode_initializer(&[address(1), address(2)], 2, address(3));
+        // setup(address[],uint256,address,bytes,address,address,uint256,address)
+        assert_eq!(hex::encode(&initializer[..4]), "b63e800d");
+    }
+
+    #[test]
+    fn create2_address_matches_create_proxy_with_nonce() {
+        let proxy_factory = address(0xfa);
+        let master_copy = address(0xaa);
+        let creation_code = hex::decode("608060405234801561001057600080fd5b50").unwrap();
+        let initializer = encode_initializer(&[address(1)], 1, address(3));
+        let salt_nonce = U256::from(42);
+
+        // salt = keccak256(keccak256(initializer) ++ saltNonce), deploymentData = creationCode ++ uint256(masterCopy)
+        let salt = keccak256([keccak256(&initializer).as_slice(), &encode(&[Token::Uint(salt_nonce)])].concat());
+        let init_code = [creation_code.as_slice(), &encode(&[Token::Address(master_copy)])].concat();
+        let expected = ethers::utils::get_create2_address(proxy_factory, salt, init_code);
+
+        assert_eq!(calculate_create2_address(proxy_factory, master_copy, &creation_code, &initializer, salt_nonce), expected);
+    }
+
+    #[test]
+    fn create2_address_depends_on_every_input() {
+        let code = [0x60, 0x80];
+        let initializer = encode_initializer(&[address(1)], 1, address(3));
+        let deployed = calculate_create2_address(address(0xfa), address(0xaa), &code, &initializer, U256::one());
+
+        assert_ne!(calculate_create2_address(address(0xfb), address(0xaa), &code, &initializer, U256::one()), deployed);
+        assert_ne!(calculate_create2_address(address(0xfa), address(0xab), &code, &initializer, U256::one()), deployed);
+        assert_ne!(calculate_create2_address(address(0xfa), address(0xaa), &[0x60], &initializer, U256::one()), deployed);
+        assert_ne!(calculate_create2_address(address(0xfa), address(0xaa), &code, &initializer, U256::from(2)), deployed);
+    }
 }
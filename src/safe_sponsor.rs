@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ethers::types::Address;
+
+use crate::as_addr_err;
+use crate::safe::SafeError;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Deployments the relayer pays for per UTC day.
+#[derive(Clone, Copy)]
+pub(crate) struct SponsorQuota {
+    pub(crate) per_address: u32,
+    pub(crate) per_ip: u32,
+    pub(crate) total: u32,
+}
+
+/// Who asks for a sponsored deployment.
+pub(crate) struct Sponsee {
+    pub(crate) client_ip: Option<String>,
+    // EIP-4361 message and its signature by the user address
+    pub(crate) message: Option<String>,
+    pub(crate) signature: Option<String>,
+}
+
+#[derive(Default)]
+struct Usage {
+    day: u64,
+    by_address: HashMap<Address, u32>,
+    by_ip: HashMap<String, u32>,
+    total: u32,
+}
+
+/// Decides which deployments the relayer pays for.
+pub(crate) struct SponsorshipPolicy {
+    quota: SponsorQuota,
+    usage: Mutex<Usage>,
+}
+
+impl SponsorshipPolicy {
+    pub(crate) fn new(quota: SponsorQuota) -> Self {
+        Self {
+            quota,
+            usage: Mutex::new(Usage::default()),
+        }
+    }
+
+    /// Takes one deployment off every quota.
+    pub(crate) fn reserve(&self, user_address: &str, sponsee: &Sponsee) -> Result<(), SafeError> {
+        let user_address = as_addr_err!(user_address.parse::<Address>());
+        let mut usage = self.usage.lock().unwrap();
+        let today = now() / DAY;
+        if usage.day != today {
+            *usage = Usage { day: today, ..Usage::default() };
+        }
+        if usage.total >= self.quota.total {
+            return Err(SafeError::QuotaExceeded("daily sponsored deployments are used up".to_string()));
+        }
+        if usage.by_address.get(&user_address).copied().unwrap_or(0) >= self.quota.per_address {
+            return Err(SafeError::QuotaExceeded(format!("{user_address:?} used up its daily sponsored deployments")));
+        }
+        if let Some(ip) = &sponsee.client_ip {
+            if usage.by_ip.get(ip).copied().unwrap_or(0) >= self.quota.per_ip {
+                return Err(SafeError::QuotaExceeded(format!("{ip} used up its daily sponsored deployments")));
+            }
+            *usage.by_ip.entry(ip.clone()).or_default() += 1;
+        }
+        *usage.by_address.entry(user_address).or_default() += 1;
+        usage.total += 1;
+        Ok(())
+    }
+
+    /// Gives back a reservation whose deployment never got relayed.
+    pub(crate) fn release(&self, user_address: &str, sponsee: &Sponsee) {
+        let user_address = match user_address.parse::<Address>() {
+            Ok(user_address) => user_address,
+            Err(_) => return,
+        };
+        let mut usage = self.usage.lock().unwrap();
+        if usage.day != now() / DAY {
+            return;
+        }
+        if let Some(count) = usage.by_address.get_mut(&user_address) {
+            *count = count.saturating_sub(1);
+        }
+        if let Some(count) = sponsee.client_ip.as_ref().and_then(|ip| usage.by_ip.get_mut(ip)) {
+            *count = count.saturating_sub(1);
+        }
+        usage.total = usage.total.saturating_sub(1);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "0x1111111111111111111111111111111111111111";
+    const BOB: &str = "0x2222222222222222222222222222222222222222";
+    const CAROL: &str = "0x3333333333333333333333333333333333333333";
+
+    fn policy(per_address: u32, per_ip: u32, total: u32) -> SponsorshipPolicy {
+        SponsorshipPolicy::new(SponsorQuota { per_address, per_ip, total })
+    }
+
+    fn sponsee(client_ip: Option<&str>) -> Sponsee {
+        Sponsee {
+            client_ip: client_ip.map(str::to_string),
+            message: None,
+            signature: None,
+        }
+    }
+
+    fn exceeded(result: Result<(), SafeError>) -> String {
+        match result {
+            Err(SafeError::QuotaExceeded(message)) => message,
+            other => panic!("expected QuotaExceeded, got {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    /// Moves the usage back a day, as if it had been recorded yesterday.
+    fn yesterday(policy: &SponsorshipPolicy) {
+        policy.usage.lock().unwrap().day -= 1;
+    }
+
+    #[test]
+    fn deployments_are_limited_per_address() {
+        let policy = policy(2, 10, 10);
+        let sponsee = sponsee(None);
+        assert!(policy.reserve(ALICE, &sponsee).is_ok());
+        assert!(policy.reserve(&ALICE.to_uppercase().replace("0X", "0x"), &sponsee).is_ok());
+        assert_eq!(exceeded(policy.reserve(ALICE, &sponsee)), format!("{ALICE} used up its daily sponsored deployments"));
+        assert!(policy.reserve(BOB, &sponsee).is_ok());
+    }
+
+    #[test]
+    fn deployments_are_limited_per_ip() {
+        let policy = policy(10, 2, 10);
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert!(policy.reserve(BOB, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert_eq!(exceeded(policy.reserve(CAROL, &sponsee(Some("10.0.0.1")))), "10.0.0.1 used up its daily sponsored deployments");
+        assert!(policy.reserve(CAROL, &sponsee(Some("10.0.0.2"))).is_ok());
+        // requests without a known IP are only limited by the other quotas
+        assert!(policy.reserve(CAROL, &sponsee(None)).is_ok());
+    }
+
+    #[test]
+    fn deployments_are_limited_per_day() {
+        let policy = policy(10, 10, 2);
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert!(policy.reserve(BOB, &sponsee(Some("10.0.0.2"))).is_ok());
+        assert_eq!(exceeded(policy.reserve(CAROL, &sponsee(Some("10.0.0.3")))), "daily sponsored deployments are used up");
+    }
+
+    #[test]
+    fn refused_deployments_use_up_no_quota() {
+        let policy = policy(1, 2, 10);
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_err());
+        assert!(policy.reserve(BOB, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert!(matches!(policy.reserve("0x12", &sponsee(None)), Err(SafeError::BadAddress(_))));
+        assert_eq!(policy.usage.lock().unwrap().total, 2);
+    }
+
+    #[test]
+    fn quotas_roll_over_daily() {
+        let policy = policy(1, 1, 1);
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_err());
+        yesterday(&policy);
+        assert!(policy.reserve(ALICE, &sponsee(Some("10.0.0.1"))).is_ok());
+        assert!(policy.reserve(BOB, &sponsee(None)).is_err());
+    }
+
+    #[test]
+    fn releases_restore_the_quota() {
+        let policy = policy(1, 1, 1);
+        let sponsee = sponsee(Some("10.0.0.1"));
+        assert!(policy.reserve(ALICE, &sponsee).is_ok());
+        policy.release(ALICE, &sponsee);
+        assert!(policy.reserve(ALICE, &sponsee).is_ok());
+        assert!(policy.reserve(ALICE, &sponsee).is_err());
+
+        // a bad address or a second release never goes below nothing used
+        policy.release("0x12", &sponsee);
+        policy.release(ALICE, &sponsee);
+        policy.release(ALICE, &sponsee);
+        let usage = policy.usage.lock().unwrap();
+        assert_eq!((usage.total, usage.by_address[&ALICE.parse().unwrap()], usage.by_ip["10.0.0.1"]), (0, 0, 0));
+    }
+
+    #[test]
+    fn releases_from_a_past_day_are_ignored() {
+        let policy = policy(1, 1, 1);
+        let sponsee = sponsee(Some("10.0.0.1"));
+        assert!(policy.reserve(ALICE, &sponsee).is_ok());
+        yesterday(&policy);
+        assert!(policy.reserve(BOB, &sponsee).is_ok());
+        yesterday(&policy);
+        policy.release(BOB, &sponsee);
+        assert_eq!(policy.usage.lock().unwrap().total, 1);
+    }
+}
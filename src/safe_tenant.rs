@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::HttpRequest;
+use ethers::types::{Address, U256};
+use ethers::utils::{hex, to_checksum};
+
+use crate::safe::{SafeError, SafeSetup};
+use crate::safe_config::TenantConfig;
+use crate::safe_rate_limit::{api_route, RateLimiter, TokenBucket};
+use crate::safe_sponsor::{SponsorQuota, SponsorshipPolicy};
+
+pub(crate) const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// One app using the relay, with its own limits and Safe defaults.
+pub(crate) struct Tenant {
+    pub(crate) name: String,
+    // empty allows any origin
+    allowed_origins: Vec<String>,
+    // shared by every key of the tenant
+    requests: Option<Mutex<TokenBucket>>,
+    pub(crate) sponsorship: SponsorshipPolicy,
+    fallback_handler: Option<String>,
+    salt_nonce: Option<U256>,
+}
+
+impl Tenant {
+    fn new(config: &TenantConfig, default_quota: SponsorQuota) -> Result<Self, String> {
+        let sponsorship = config.sponsorship.clone().unwrap_or_default();
+        let quota = SponsorQuota {
+            per_address: sponsorship.per_address.unwrap_or(default_quota.per_address),
+            per_ip: sponsorship.per_ip.unwrap_or(default_quota.per_ip),
+            total: sponsorship.per_day.unwrap_or(default_quota.total),
+        };
+        let fallback_handler = match &config.fallback_address {
+            Some(fallback) => Some(fallback.parse::<Address>()
+                .map_err(|e| format!("fallbackAddress of tenant {} must be an address: {e}", config.name))?),
+            None => None,
+        };
+        let salt_nonce = match &config.salt_nonce {
+            Some(salt_nonce) => match hex::decode(salt_nonce) {
+                Ok(salt_nonce) if salt_nonce.len() <= 32 => Some(U256::from(salt_nonce.as_slice())),
+                _ => return Err(format!("saltNonce of tenant {} must be at most 32 bytes of hex, not {salt_nonce}", config.name)),
+            },
+            None => None,
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            allowed_origins: config.allowed_origins.clone(),
+            requests: config.requests_per_minute.map(|limit| Mutex::new(TokenBucket::per_minute(limit))),
+            sponsorship: SponsorshipPolicy::new(quota),
+            fallback_handler: fallback_handler.map(|fallback| to_checksum(&fallback, None)),
+            salt_nonce,
+        })
+    }
+
+    /// Fills in the tenant's fallback handler and salt nonce where the request has none.
+    pub(crate) fn setup(&self, setup: SafeSetup) -> SafeSetup {
+        SafeSetup {
+            fallback_handler: setup.fallback_handler.or_else(|| self.fallback_handler.clone()),
+            salt_nonce: setup.salt_nonce.or_else(|| self.salt_nonce.map(|salt_nonce| salt_nonce.to_string())),
+            ..setup
+        }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.is_empty() || self.allowed_origins.iter().any(|allowed| allowed == origin)
+    }
+
+    fn take_request(&self) -> Result<(), SafeError> {
+        match &self.requests {
+            Some(requests) => requests.lock().unwrap()
+                .take()
+                .map_err(|wait| SafeError::RateLimited(wait.as_secs_f64().ceil() as u64)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Maps API keys to tenants. Without configured tenants every request
+/// belongs to an open default tenant and no key is needed.
+pub(crate) struct Tenants {
+    by_key: HashMap<String, Arc<Tenant>>,
+    open: Option<Arc<Tenant>>,
+    allowed_origins: Option<Vec<String>>,
+}
+
+impl Tenants {
+    pub(crate) fn new(configs: Option<&[TenantConfig]>, default_quota: SponsorQuota) -> Result<Self, String> {
+        let configs = match configs {
+            Some(configs) => configs,
+            None => {
+                let open = TenantConfig {
+                    name: "default".to_string(),
+                    ..TenantConfig::default()
+                };
+                return Ok(Self {
+                    by_key: HashMap::new(),
+                    open: Some(Arc::new(Tenant::new(&open, default_quota)?)),
+                    allowed_origins: None,
+                });
+            }
+        };
+
+        let mut by_key = HashMap::new();
+        for config in configs {
+            let tenant = Arc::new(Tenant::new(config, default_quota)?);
+            for api_key in &config.api_keys {
+                if by_key.insert(api_key.clone(), tenant.clone()).is_some() {
+                    return Err(format!("API key of tenant {} is used by another tenant", config.name));
+                }
+            }
+        }
+        // a tenant without origins accepts browsers from anywhere
+        let allowed_origins = if configs.iter().any(|config| config.allowed_origins.is_empty()) {
+            None
+        } else {
+            Some(configs.iter().flat_map(|config| config.allowed_origins.clone()).collect())
+        };
+        Ok(Self {
+            by_key,
+            open: None,
+            allowed_origins,
+        })
+    }
+
+    /// Origins CORS lets through, `None` for any.
+    pub(crate) fn allowed_origins(&self) -> Option<Vec<String>> {
+        self.allowed_origins.clone()
+    }
+
+    /// The API key of the request when it belongs to a tenant.
+    fn api_key<'a>(&self, req: &'a HttpRequest) -> Option<&'a str> {
+        req.headers().get(API_KEY_HEADER)
+            .and_then(|api_key| api_key.to_str().ok())
+            .filter(|api_key| self.by_key.contains_key(*api_key))
+    }
+
+    /// Resolves the tenant of an API request, `None` for routes open to everyone.
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<Arc<Tenant>>, SafeError> {
+        match api_route(req.path()).as_deref() {
+            None | Some(["health"]) => return Ok(None),
+            Some(_) => {}
+        }
+        let tenant = match &self.open {
+            Some(open) => open.clone(),
+            None => {
+                let api_key = req.headers().get(API_KEY_HEADER)
+                    .and_then(|api_key| api_key.to_str().ok())
+                    .ok_or_else(|| SafeError::Unauthorized(format!("{API_KEY_HEADER} header is required")))?;
+                self.by_key.get(api_key)
+                    .cloned()
+                    .ok_or_else(|| SafeError::Unauthorized("unknown API key".to_string()))?
+            }
+        };
+        if let Some(origin) = req.headers().get("Origin").and_then(|origin| origin.to_str().ok()) {
+            if !tenant.allows_origin(origin) {
+                return Err(SafeError::Forbidden(format!("origin {origin} is not allowed for {}", tenant.name)));
+            }
+        }
+        Ok(Some(tenant))
+    }
+
+    /// Authenticates an API request and takes it from the rate limits, then from the tenant's own
+    /// limit, so that a request the rate limiter turns away is not counted against the tenant.
+    /// Only authenticated requests are counted, anyone else could drain a victim's buckets.
+    pub(crate) fn admit(&self, req: &HttpRequest, rate_limiter: &RateLimiter) -> Result<Option<Arc<Tenant>>, SafeError> {
+        let tenant = self.authenticate(req)?;
+        rate_limiter.check(req, self.api_key(req))?;
+        if let Some(tenant) = &tenant {
+            tenant.take_request()?;
+        }
+        Ok(tenant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use crate::safe_rate_limit::RateLimits;
+
+    use super::*;
+
+    const QUOTA: SponsorQuota = SponsorQuota { per_address: 1, per_ip: 10, total: 1000 };
+
+    fn request(api_key: &str) -> HttpRequest {
+        TestRequest::get()
+            .uri("/v1/safe/0xab")
+            .peer_addr("10.0.0.1:40000".parse().unwrap())
+            .insert_header((API_KEY_HEADER, api_key))
+            .to_http_request()
+    }
+
+    fn tenant(name: &str, api_keys: &[&str], allowed_origins: &[&str]) -> TenantConfig {
+        TenantConfig {
+            name: name.to_string(),
+            api_keys: api_keys.iter().map(|api_key| api_key.to_string()).collect(),
+            allowed_origins: allowed_origins.iter().map(|origin| origin.to_string()).collect(),
+            ..TenantConfig::default()
+        }
+    }
+
+    fn tenants() -> Tenants {
+        Tenants::new(Some(&[
+            tenant("wallet", &["wallet key", "wallet key 2"], &["https://wallet.example.com"]),
+            tenant("game", &["game key"], &["https://game.example.com", "https://play.example.com"]),
+        ]), QUOTA).unwrap()
+    }
+
+    fn authenticate(tenants: &Tenants, req: TestRequest) -> Result<Option<String>, SafeError> {
+        tenants.authenticate(&req.to_http_request()).map(|tenant| tenant.map(|tenant| tenant.name.clone()))
+    }
+
+    #[test]
+    fn requests_are_authenticated_by_api_key() {
+        let tenants = tenants();
+        let cases = [
+            ("wallet key", "/v1/safe/0xab", Ok(Some("wallet"))),
+            ("wallet key 2", "/v1/5/safe/0xab", Ok(Some("wallet"))),
+            ("game key", "/v1/safe/0xab/tx", Ok(Some("game"))),
+            ("unknown", "/v1/safe/0xab", Err("unknown API key")),
+            ("Wallet Key", "/v1/safe/0xab", Err("unknown API key")),
+            // open routes need no key
+            ("unknown", "/v1/health", Ok(None)),
+            ("unknown", "/v1/5/health", Ok(None)),
+            ("unknown", "/swagger/index.html", Ok(None)),
+        ];
+        for (api_key, path, tenant) in cases {
+            let req = TestRequest::get().uri(path).insert_header((API_KEY_HEADER, api_key));
+            match (authenticate(&tenants, req), tenant) {
+                (Ok(name), Ok(expected)) => assert_eq!(name.as_deref(), expected, "{api_key} {path}"),
+                (Err(SafeError::Unauthorized(message)), Err(expected)) => assert_eq!(message, expected, "{api_key} {path}"),
+                (other, _) => panic!("{api_key} {path}: unexpected {:?}", other.map_err(|e| e.to_string())),
+            }
+        }
+        assert!(matches!(authenticate(&tenants, TestRequest::get().uri("/v1/safe/0xab")),
+                         Err(SafeError::Unauthorized(message)) if message == format!("{API_KEY_HEADER} header is required")));
+    }
+
+    #[test]
+    fn requests_are_open_without_tenants() {
+        let tenants = Tenants::new(None, QUOTA).unwrap();
+        let name = authenticate(&tenants, TestRequest::get().uri("/v1/safe/0xab").insert_header(("Origin", "https://any.example.com")));
+        assert_eq!(name.ok().flatten().as_deref(), Some("default"));
+        assert_eq!(tenants.allowed_origins(), None);
+        let req = TestRequest::get().uri("/v1/safe/0xab").insert_header((API_KEY_HEADER, "any key")).to_http_request();
+        assert_eq!(tenants.api_key(&req), None);
+    }
+
+    #[test]
+    fn api_keys_are_looked_up() {
+        let tenants = tenants();
+        let api_key = |api_key: &str| {
+            let req = TestRequest::get().uri("/v1/safe/0xab").insert_header((API_KEY_HEADER, api_key)).to_http_request();
+            tenants.api_key(&req).map(str::to_string)
+        };
+        assert_eq!(api_key("game key").as_deref(), Some("game key"));
+        assert_eq!(api_key("unknown"), None);
+        assert_eq!(tenants.api_key(&TestRequest::get().uri("/v1/safe/0xab").to_http_request()), None);
+    }
+
+    #[test]
+    fn origins_are_matched_per_tenant() {
+        let tenants = tenants();
+        let cases = [
+            ("wallet key", "https://wallet.example.com", true),
+            ("game key", "https://play.example.com", true),
+            ("game key", "https://wallet.example.com", false),
+            ("wallet key", "https://wallet.example.com:8443", false),
+            ("wallet key", "http://wallet.example.com", false),
+            ("wallet key", "https://wallet.example.com.evil.com", false),
+        ];
+        for (api_key, origin, allowed) in cases {
+            let req = TestRequest::get().uri("/v1/safe/0xab")
+                .insert_header((API_KEY_HEADER, api_key))
+                .insert_header(("Origin", origin));
+            match authenticate(&tenants, req) {
+                Ok(_) => assert!(allowed, "{api_key} {origin}"),
+                Err(SafeError::Forbidden(message)) => {
+                    assert!(!allowed, "{api_key} {origin}");
+                    assert!(message.starts_with(&format!("origin {origin} is not allowed")), "{message}");
+                }
+                Err(e) => panic!("{api_key} {origin}: unexpected {e}"),
+            }
+        }
+        // requests without an origin do not come from a browser
+        assert!(authenticate(&tenants, TestRequest::get().uri("/v1/safe/0xab").insert_header((API_KEY_HEADER, "game key"))).is_ok());
+    }
+
+    #[test]
+    fn cors_lets_through_the_origins_of_every_tenant() {
+        assert_eq!(tenants().allowed_origins(), Some(vec![
+            "https://wallet.example.com".to_string(),
+            "https://game.example.com".to_string(),
+            "https://play.example.com".to_string(),
+        ]));
+        let tenants = Tenants::new(Some(&[
+            tenant("wallet", &["wallet key"], &["https://wallet.example.com"]),
+            tenant("backend", &["backend key"], &[]),
+        ]), QUOTA).unwrap();
+        assert_eq!(tenants.allowed_origins(), None);
+        let req = TestRequest::get().uri("/v1/safe/0xab")
+            .insert_header((API_KEY_HEADER, "backend key"))
+            .insert_header(("Origin", "https://any.example.com"));
+        assert!(authenticate(&tenants, req).is_ok());
+    }
+
+    #[test]
+    fn bad_tenants_are_refused() {
+        let cases = [
+            (TenantConfig { fallback_address: Some("0x12".to_string()), ..tenant("wallet", &[], &[]) },
+             "fallbackAddress of tenant wallet must be an address"),
+            (TenantConfig { salt_nonce: Some("xyz".to_string()), ..tenant("wallet", &[], &[]) },
+             "saltNonce of tenant wallet must be at most 32 bytes of hex, not xyz"),
+            (TenantConfig { salt_nonce: Some("00".repeat(33)), ..tenant("wallet", &[], &[]) },
+             "saltNonce of tenant wallet must be at most 32 bytes of hex"),
+        ];
+        for (config, error) in cases {
+            match Tenants::new(Some(&[config]), QUOTA) {
+                Err(e) => assert!(e.starts_with(error), "{e}"),
+                Ok(_) => panic!("expected {error}"),
+            }
+        }
+        let duplicate = Tenants::new(Some(&[tenant("wallet", &["key"], &[]), tenant("game", &["key"], &[])]), QUOTA);
+        assert_eq!(duplicate.err().as_deref(), Some("API key of tenant game is used by another tenant"));
+    }
+
+    #[test]
+    fn tenants_fill_in_their_setup_defaults() {
+        let tenants = Tenants::new(Some(&[TenantConfig {
+            fallback_address: Some("0xf48f2b2d2a534e402487b3ee7c18c33aec0fe5e4".to_string()),
+            salt_nonce: Some("0a".to_string()),
+            ..tenant("wallet", &["key"], &[])
+        }]), QUOTA).unwrap();
+        let tenant = &tenants.by_key["key"];
+        let setup = tenant.setup(SafeSetup::default());
+        assert_eq!(setup.fallback_handler.as_deref(), Some("0xf48f2B2d2a534e402487b3ee7C18c33Aec0Fe5e4"));
+        assert_eq!(setup.salt_nonce.as_deref(), Some("10"));
+        let setup = tenant.setup(SafeSetup { salt_nonce: Some("7".to_string()), ..SafeSetup::default() });
+        assert_eq!(setup.salt_nonce.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn rate_limited_requests_are_not_counted_against_the_tenant() {
+        let tenants = Tenants::new(Some(&[TenantConfig {
+            name: "app".to_string(),
+            api_keys: vec!["key".to_string()],
+            requests_per_minute: Some(2),
+            ..TenantConfig::default()
+        }]), QUOTA).unwrap();
+        let rate_limiter = RateLimiter::new(RateLimits { read: 1, deploy: 1, exec: 1 });
+
+        assert!(tenants.admit(&request("key"), &rate_limiter).is_ok());
+        assert!(matches!(tenants.admit(&request("key"), &rate_limiter), Err(SafeError::RateLimited(_))));
+        let tenant = tenants.by_key["key"].clone();
+        assert!(tenant.take_request().is_ok());
+        assert!(tenant.take_request().is_err());
+    }
+}
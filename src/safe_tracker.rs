@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use ethers::core::rand;
+use ethers::core::types::transaction::eip2718::TypedTransaction;
+use ethers::prelude::*;
+use ethers::utils::hex;
+use log::{debug, warn};
+
+use crate::safe::{RelayState, RelayStatus};
+use crate::safe_relayer::Relayer;
+use crate::safe_service::Signer;
+
+// finished relays are kept around for clients polling late
+const RELAY_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// nodes only accept a replacement raising every fee by at least this much
+pub(crate) const MIN_BUMP_PERCENT: u64 = 10;
+
+/// How transactions stuck in the mempool get re-priced.
+#[derive(Clone, Copy)]
+pub(crate) struct GasBump {
+    pub(crate) percent: u64,
+    pub(crate) max_fee: U256,
+    pub(crate) interval: Duration,
+}
+
+struct Relay {
+    relayer: Arc<Relayer>,
+    // latest broadcast version of the transaction
+    tx: TypedTransaction,
+    // every hash broadcast for the nonce, oldest first
+    tx_hashes: Vec<H256>,
+    broadcast_at: Instant,
+    status: RelayStatus,
+    updated_at: Instant,
+}
+
+enum Outcome {
+    Included(Box<TransactionReceipt>),
+    Replaced,
+    Pending,
+    // stuck, but the fee cap leaves no room for a replacement nodes accept
+    Capped,
+    Rebroadcast(Box<TypedTransaction>, H256),
+}
+
+/// Follows relayed transactions in the background until they are final,
+/// re-pricing the ones stuck in the mempool.
+#[derive(Clone)]
+pub(crate) struct RelayTracker {
+    relays: Arc<RwLock<HashMap<String, Relay>>>,
+}
+
+impl RelayTracker {
+    pub(crate) fn new(provider: Provider<Http>, poll_interval: Duration, confirmations: u64, gas_bump: GasBump) -> Self {
+        let tracker = Self {
+            relays: Arc::new(RwLock::new(HashMap::new())),
+        };
+
+        let background = tracker.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(poll_interval);
+            loop {
+                interval.tick().await;
+                background.poll(&provider, confirmations, &gas_bump).await;
+            }
+        });
+
+        tracker
+    }
+
+    /// Registers a broadcast transaction and returns its relay id.
+    pub(crate) fn track(&self, relayer: Arc<Relayer>, tx: TypedTransaction, tx_hash: H256) -> String {
+        let id = format!("0x{}", hex::encode(rand::random::<[u8; 16]>()));
+        let status = RelayStatus {
+            id: id.clone(),
+            status: RelayState::Pending,
+            transaction_hash: format!("{tx_hash:?}"),
+            transaction_hashes: vec![format!("{tx_hash:?}")],
+            confirmations: 0,
+            block_hash: None,
+            block_number: None,
+            gas_used: None,
+            fee_capped: false,
+        };
+        self.relays.write().unwrap().insert(id.clone(), Relay {
+            relayer,
+            tx,
+            tx_hashes: vec![tx_hash],
+            broadcast_at: Instant::now(),
+            status,
+            updated_at: Instant::now(),
+        });
+        id
+    }
+
+    pub(crate) fn status(&self, id: &str) -> Option<RelayStatus> {
+        self.relays.read().unwrap().get(id).map(|relay| relay.status.clone())
+    }
+
+    async fn poll(&self, provider: &Provider<Http>, confirmations: u64, gas_bump: &GasBump) {
+        self.relays.write().unwrap().retain(|_, relay| {
+            relay.status.status == RelayState::Pending || relay.updated_at.elapsed() < RELAY_RETENTION
+        });
+
+        let latest_block = match provider.get_block_number().await {
+            Ok(block) => block.as_u64(),
+            Err(e) => {
+                warn!("Relay tracker could not fetch block number: {e}");
+                return;
+            }
+        };
+
+        let unfinished: Vec<_> = self.relays.read().unwrap()
+            .iter()
+            .filter(|(_, relay)| match relay.status.status {
+                RelayState::Pending => true,
+                RelayState::Mined | RelayState::Failed => relay.status.confirmations < confirmations,
+                RelayState::Replaced => false,
+            })
+            .map(|(id, relay)| (id.clone(), relay.relayer.clone(), relay.tx.clone(), relay.tx_hashes.clone(), relay.broadcast_at, relay.status.fee_capped))
+            .collect();
+
+        for (id, relayer, tx, tx_hashes, broadcast_at, fee_capped) in unfinished {
+            let stuck = !fee_capped && broadcast_at.elapsed() >= gas_bump.interval;
+            let outcome = match Self::check(provider, &relayer.client(), &tx, &tx_hashes, stuck, gas_bump).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!("Relay tracker could not check relay {id}: {e}");
+                    continue;
+                }
+            };
+
+            let mut relays = self.relays.write().unwrap();
+            let relay = match relays.get_mut(&id) {
+                Some(relay) => relay,
+                None => continue,
+            };
+            if matches!(outcome, Outcome::Included(_) | Outcome::Replaced) {
+                if let Some(nonce) = relay.tx.nonce() {
+                    relay.relayer.release(*nonce);
+                }
+            }
+            let status = &mut relay.status;
+            match outcome {
+                Outcome::Included(receipt) => {
+                    status.status = match receipt.status {
+                        Some(status) if status.is_zero() => RelayState::Failed,
+                        _ => RelayState::Mined,
+                    };
+                    status.transaction_hash = format!("{:?}", receipt.transaction_hash);
+                    status.confirmations = receipt.block_number
+                        .map(|block| latest_block.saturating_sub(block.as_u64()) + 1)
+                        .unwrap_or(0);
+                    status.block_hash = receipt.block_hash.map(|hash| format!("{hash:?}"));
+                    status.block_number = receipt.block_number.map(|block| block.as_u64());
+                    status.gas_used = receipt.gas_used.map(|gas| gas.to_string());
+                }
+                Outcome::Replaced => status.status = RelayState::Replaced,
+                Outcome::Pending => {
+                    // a mined transaction may return to the mempool after a reorg
+                    status.status = RelayState::Pending;
+                    status.confirmations = 0;
+                    status.block_hash = None;
+                    status.block_number = None;
+                    status.gas_used = None;
+                }
+                Outcome::Capped => {
+                    warn!("Relay {id} is stuck at the fee cap, it is no longer bumped");
+                    status.fee_capped = true;
+                }
+                Outcome::Rebroadcast(tx, tx_hash) => {
+                    status.status = RelayState::Pending;
+                    status.transaction_hash = format!("{tx_hash:?}");
+                    if !relay.tx_hashes.contains(&tx_hash) {
+                        relay.tx_hashes.push(tx_hash);
+                        status.transaction_hashes.push(format!("{tx_hash:?}"));
+                    }
+                    relay.tx = *tx;
+                    relay.broadcast_at = Instant::now();
+                }
+            }
+            debug!("Relay {id} is {:?} as {}", status.status, status.transaction_hash);
+            relay.updated_at = Instant::now();
+        }
+    }
+
+    async fn check(provider: &Provider<Http>,
+                   client: &Signer,
+                   tx: &TypedTransaction,
+                   tx_hashes: &[H256],
+                   stuck: bool,
+                   gas_bump: &GasBump) -> Result<Outcome, ProviderError> {
+        // any of the replacements may be the one that got mined
+        for tx_hash in tx_hashes.iter().rev() {
+            if let Some(receipt) = provider.get_transaction_receipt(*tx_hash).await? {
+                return Ok(Outcome::Included(Box::new(receipt)));
+            }
+        }
+
+        let (from, nonce) = match (tx.from(), tx.nonce()) {
+            (Some(from), Some(nonce)) => (*from, *nonce),
+            _ => return Ok(Outcome::Pending),
+        };
+        let mined_nonce = provider.get_transaction_count(from, Some(BlockNumber::Latest.into())).await?;
+        if mined_nonce > nonce {
+            // one of ours may have been mined since its receipt was looked up
+            for tx_hash in tx_hashes.iter().rev() {
+                if let Some(receipt) = provider.get_transaction_receipt(*tx_hash).await? {
+                    return Ok(Outcome::Included(Box::new(receipt)));
+                }
+            }
+            // the nonce got used by a transaction that is none of ours
+            return Ok(Outcome::Replaced);
+        }
+
+        let latest_hash = tx_hashes[tx_hashes.len() - 1];
+        let replacement = if provider.get_transaction(latest_hash).await?.is_none() {
+            // a dropped transaction blocks every later nonce, so it is sent again
+            warn!("Relay transaction {latest_hash:?} with nonce {nonce} was dropped, rebroadcasting");
+            Some(tx.clone())
+        } else if stuck {
+            match bump(tx, gas_bump) {
+                Some(bumped) => {
+                    debug!("Relay transaction {latest_hash:?} with nonce {nonce} is stuck, bumping fees");
+                    Some(bumped)
+                }
+                None => return Ok(Outcome::Capped),
+            }
+        } else {
+            None
+        };
+
+        match replacement {
+            Some(replacement) => match client.send_transaction(replacement.clone(), None).await {
+                Ok(pending) => Ok(Outcome::Rebroadcast(Box::new(replacement), pending.tx_hash())),
+                Err(e) => {
+                    warn!("Could not rebroadcast {latest_hash:?}: {e}");
+                    Ok(Outcome::Pending)
+                }
+            },
+            None => Ok(Outcome::Pending),
+        }
+    }
+}
+
+/// Raises every fee of `tx` by the configured percentage, and at least by the
+/// replacement minimum, `None` when that would go over the cap.
+fn bump(tx: &TypedTransaction, gas_bump: &GasBump) -> Option<TypedTransaction> {
+    let percent = gas_bump.percent.max(MIN_BUMP_PERCENT);
+    // rounded up, nodes compare against the old fee times 1.1
+    let raise = |fee: U256| (fee * (100 + percent) + 99) / 100;
+
+    let mut bumped = tx.clone();
+    match &mut bumped {
+        TypedTransaction::Eip1559(inner) => {
+            let priority_fee = inner.max_priority_fee_per_gas.map(raise);
+            // the priority fee is part of the max fee, so it can push the max fee up further
+            let max_fee = raise(inner.max_fee_per_gas?).max(priority_fee.unwrap_or_default());
+            if max_fee > gas_bump.max_fee {
+                return None;
+            }
+            inner.max_fee_per_gas = Some(max_fee);
+            inner.max_priority_fee_per_gas = priority_fee;
+        }
+        TypedTransaction::Legacy(inner) | TypedTransaction::Eip2930(Eip2930TransactionRequest { tx: inner, .. }) => {
+            let gas_price = raise(inner.gas_price?);
+            if gas_price > gas_bump.max_fee {
+                return None;
+            }
+            inner.gas_price = Some(gas_price);
+        }
+    }
+    Some(bumped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gas_bump(percent: u64, max_fee: u64) -> GasBump {
+        GasBump {
+            percent,
+            max_fee: U256::from(max_fee),
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    fn eip1559(max_fee: u64, priority_fee: u64) -> TypedTransaction {
+        Eip1559TransactionRequest::new()
+            .max_fee_per_gas(max_fee)
+            .max_priority_fee_per_gas(priority_fee)
+            .into()
+    }
+
+    fn fees(tx: &TypedTransaction) -> (U256, U256) {
+        match tx {
+            TypedTransaction::Eip1559(inner) => (inner.max_fee_per_gas.unwrap(), inner.max_priority_fee_per_gas.unwrap()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn raises_both_fees_by_the_percentage() {
+        let bumped = bump(&eip1559(1_000, 100), &gas_bump(20, 10_000)).unwrap();
+
+        assert_eq!(fees(&bumped), (U256::from(1_200), U256::from(120)));
+    }
+
+    #[test]
+    fn raises_both_fees_by_at_least_ten_percent_rounded_up() {
+        let bumped = bump(&eip1559(1_001, 11), &gas_bump(5, 10_000)).unwrap();
+
+        // 1001 * 1.1 = 1101.1 and 11 * 1.1 = 12.1
+        assert_eq!(fees(&bumped), (U256::from(1_102), U256::from(13)));
+    }
+
+    #[test]
+    fn keeps_the_priority_fee_within_the_max_fee() {
+        let bumped = bump(&eip1559(1_000, 1_000), &gas_bump(10, 10_000)).unwrap();
+
+        assert_eq!(fees(&bumped), (U256::from(1_100), U256::from(1_100)));
+    }
+
+    #[test]
+    fn stops_at_the_cap() {
+        assert!(bump(&eip1559(1_000, 100), &gas_bump(10, 1_100)).is_some());
+        // clamping to the cap would be less than the replacement minimum
+        assert!(bump(&eip1559(1_000, 100), &gas_bump(10, 1_099)).is_none());
+        assert!(bump(&eip1559(1_000, 100), &gas_bump(10, 1_000)).is_none());
+    }
+
+    #[test]
+    fn bumps_legacy_gas_prices() {
+        let legacy: TypedTransaction = TransactionRequest::new().gas_price(1_000).into();
+
+        match bump(&legacy, &gas_bump(10, 1_100)) {
+            Some(TypedTransaction::Legacy(inner)) => assert_eq!(inner.gas_price, Some(U256::from(1_100))),
+            _ => panic!("legacy transaction not bumped"),
+        }
+        assert!(bump(&legacy, &gas_bump(10, 1_099)).is_none());
+    }
+}
@@ -0,0 +1,383 @@
+use ethers::abi::{encode, Token};
+use ethers::types::{Address, Bytes, H256, RecoveryMessage, Signature, U256};
+use ethers::utils::{keccak256, to_checksum};
+use serde_json::{json, Value};
+
+use crate::safe::SafeError;
+
+const SAFE_TX_TYPE: &str = "SafeTx(address to,uint256 value,bytes data,uint8 operation,uint256 safeTxGas,uint256 baseGas,uint256 gasPrice,address gasToken,address refundReceiver,uint256 nonce)";
+
+// Safe < 1.3.0 uses `EIP712Domain(address verifyingContract)` without the chain id
+const DOMAIN_WITH_CHAIN_ID_TYPE: &str = "EIP712Domain(uint256 chainId,address verifyingContract)";
+const DOMAIN_TYPE: &str = "EIP712Domain(address verifyingContract)";
+
+pub(crate) const SIGNATURE_LENGTH: usize = 65;
+
+/// The call a Safe transaction makes.
+#[derive(Clone, Debug)]
+pub(crate) struct InnerCall {
+    pub(crate) to: Address,
+    pub(crate) value: U256,
+    pub(crate) data: Bytes,
+    pub(crate) operation: u8,
+}
+
+/// Transaction as it is hashed and executed by `execTransaction`.
+#[derive(Clone, Debug)]
+pub(crate) struct SafeTx {
+    pub(crate) to: Address,
+    pub(crate) value: U256,
+    pub(crate) data: Bytes,
+    pub(crate) operation: u8,
+    pub(crate) safe_tx_gas: U256,
+    pub(crate) base_gas: U256,
+    pub(crate) gas_price: U256,
+    pub(crate) gas_token: Address,
+    pub(crate) refund_receiver: Address,
+    pub(crate) nonce: U256,
+}
+
+impl SafeTx {
+    pub(crate) fn struct_hash(&self) -> [u8; 32] {
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(SAFE_TX_TYPE).to_vec()),
+            Token::Address(self.to),
+            Token::Uint(self.value),
+            Token::FixedBytes(keccak256(&self.data).to_vec()),
+            Token::Uint(U256::from(self.operation)),
+            Token::Uint(self.safe_tx_gas),
+            Token::Uint(self.base_gas),
+            Token::Uint(self.gas_price),
+            Token::Address(self.gas_token),
+            Token::Address(self.refund_receiver),
+            Token::Uint(self.nonce),
+        ]))
+    }
+
+    /// Pre-image of the SafeTx hash, same as `encodeTransactionData`.
+    pub(crate) fn encode_data(&self, domain_separator: [u8; 32]) -> Vec<u8> {
+        [&[0x19, 0x01], domain_separator.as_slice(), self.struct_hash().as_slice()].concat()
+    }
+
+    pub(crate) fn hash(&self, domain_separator: [u8; 32]) -> H256 {
+        H256::from(keccak256(self.encode_data(domain_separator)))
+    }
+
+    /// EIP-712 typed data as expected by `eth_signTypedData_v4`.
+    /// The domain layout is picked by matching the Safe's own domain separator.
+    pub(crate) fn typed_data(&self, safe: Address, chain_id: U256, domain_separator: [u8; 32]) -> Value {
+        let with_chain_id = self::domain_separator(safe, chain_id, true) == domain_separator;
+
+        let (domain_type, domain) = if with_chain_id {
+            (
+                json!([
+                    { "name": "chainId", "type": "uint256" },
+                    { "name": "verifyingContract", "type": "address" },
+                ]),
+                json!({ "chainId": chain_id.as_u64(), "verifyingContract": to_checksum(&safe, None) }),
+            )
+        } else {
+            (
+                json!([{ "name": "verifyingContract", "type": "address" }]),
+                json!({ "verifyingContract": to_checksum(&safe, None) }),
+            )
+        };
+
+        json!({
+            "types": {
+                "EIP712Domain": domain_type,
+                "SafeTx": [
+                    { "name": "to", "type": "address" },
+                    { "name": "value", "type": "uint256" },
+                    { "name": "data", "type": "bytes" },
+                    { "name": "operation", "type": "uint8" },
+                    { "name": "safeTxGas", "type": "uint256" },
+                    { "name": "baseGas", "type": "uint256" },
+                    { "name": "gasPrice", "type": "uint256" },
+                    { "name": "gasToken", "type": "address" },
+                    { "name": "refundReceiver", "type": "address" },
+                    { "name": "nonce", "type": "uint256" },
+                ],
+            },
+            "primaryType": "SafeTx",
+            "domain": domain,
+            "message": {
+                "to": to_checksum(&self.to, None),
+                "value": self.value.to_string(),
+                "data": self.data.to_string(),
+                "operation": self.operation,
+                "safeTxGas": self.safe_tx_gas.to_string(),
+                "baseGas": self.base_gas.to_string(),
+                "gasPrice": self.gas_price.to_string(),
+                "gasToken": to_checksum(&self.gas_token, None),
+                "refundReceiver": to_checksum(&self.refund_receiver, None),
+                "nonce": self.nonce.to_string(),
+            },
+        })
+    }
+}
+
+/// The domain separator the Safe at `safe` computes, also before it is deployed.
+pub(crate) fn domain_separator(safe: Address, chain_id: U256, with_chain_id: bool) -> [u8; 32] {
+    if with_chain_id {
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(DOMAIN_WITH_CHAIN_ID_TYPE).to_vec()),
+            Token::Uint(chain_id),
+            Token::Address(safe),
+        ]))
+    } else {
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(DOMAIN_TYPE).to_vec()),
+            Token::Address(safe),
+        ]))
+    }
+}
+
+/// Whether Safes of `version`, as returned by `VERSION()`, put the chain id into their domain.
+pub(crate) fn domain_has_chain_id(version: &str) -> bool {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0)) >= (1, 3)
+}
+
+/// Describes the `GSxxx` revert codes of Safe >= 1.3.0, older Safes revert with plain messages.
+pub(crate) fn describe_revert(reason: &str) -> String {
+    let description = match reason {
+        "GS000" => "Could not finish initialization",
+        "GS001" => "Threshold needs to be defined",
+        "GS010" => "Not enough gas to execute Safe transaction",
+        "GS011" => "Could not pay gas costs with ether",
+        "GS012" => "Could not pay gas costs with token",
+        "GS013" => "Safe transaction failed when gasPrice and safeTxGas were 0",
+        "GS020" => "Signatures data too short",
+        "GS021" => "Invalid contract signature location: inside static part",
+        "GS022" => "Invalid contract signature location: length not present",
+        "GS023" => "Invalid contract signature location: data not complete",
+        "GS024" => "Invalid contract signature provided",
+        "GS025" => "Hash has not been approved",
+        "GS026" => "Invalid owner provided",
+        "GS030" => "Only owners can approve a hash",
+        "GS031" => "Method can only be called from this contract",
+        "GS100" => "Modules have already been initialized",
+        "GS101" => "Invalid module address provided",
+        "GS102" => "Module has already been added",
+        "GS103" => "Invalid prevModule, module pair provided",
+        "GS104" => "Method can only be called from an enabled module",
+        "GS200" => "Owners have already been setup",
+        "GS201" => "Threshold cannot exceed owner count",
+        "GS202" => "Threshold needs to be greater than 0",
+        "GS203" => "Invalid owner address provided",
+        "GS204" => "Address is already an owner",
+        "GS205" => "Invalid prevOwner, owner pair provided",
+        "GS300" => "Guard does not implement IERC165",
+        _ => return reason.to_string(),
+    };
+    format!("{reason}: {description}")
+}
+
+/// One owner confirmation packed into `signatures`, see `checkSignatures`.
+pub(crate) enum SafeSignature {
+    Ecdsa(Address),
+    EthSign(Address),
+    ApprovedHash(Address),
+    Contract(Address, Bytes),
+}
+
+impl SafeSignature {
+    pub(crate) fn owner(&self) -> Address {
+        match self {
+            SafeSignature::Ecdsa(owner)
+            | SafeSignature::EthSign(owner)
+            | SafeSignature::ApprovedHash(owner)
+            | SafeSignature::Contract(owner, _) => *owner,
+        }
+    }
+}
+
+/// Splits `signatures` into the first `threshold` confirmations and recovers their signers.
+pub(crate) fn split_signatures(signatures: &[u8], threshold: usize, tx_hash: H256) -> Result<Vec<SafeSignature>, SafeError> {
+    let static_length = threshold * SIGNATURE_LENGTH;
+    if signatures.len() < static_length {
+        return Err(SafeError::BadSignatures(format!(
+            "expected at least {threshold} signatures of {SIGNATURE_LENGTH} bytes, got {} bytes", signatures.len()
+        )));
+    }
+
+    signatures[..static_length]
+        .chunks(SIGNATURE_LENGTH)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let r = U256::from_big_endian(&chunk[..32]);
+            let s = U256::from_big_endian(&chunk[32..64]);
+            let v = chunk[64];
+            match v {
+                0 => {
+                    let owner = Address::from_slice(&chunk[12..32]);
+                    let data = contract_signature(signatures, static_length, s)
+                        .map_err(|e| SafeError::BadSignatures(format!("signature {i}: {e}")))?;
+                    Ok(SafeSignature::Contract(owner, data))
+                }
+                1 => Ok(SafeSignature::ApprovedHash(Address::from_slice(&chunk[12..32]))),
+                27 | 28 => Signature { r, s, v: v as u64 }
+                    .recover(RecoveryMessage::Hash(tx_hash))
+                    .map(SafeSignature::Ecdsa)
+                    .map_err(|e| SafeError::BadSignatures(format!("signature {i}: {e}"))),
+                31 | 32 => Signature { r, s, v: (v - 4) as u64 }
+                    .recover(RecoveryMessage::Data(tx_hash.as_bytes().to_vec()))
+                    .map(SafeSignature::EthSign)
+                    .map_err(|e| SafeError::BadSignatures(format!("signature {i}: {e}"))),
+                _ => Err(SafeError::BadSignatures(format!("signature {i}: unsupported v {v}"))),
+            }
+        })
+        .collect()
+}
+
+fn contract_signature(signatures: &[u8], static_length: usize, offset: U256) -> Result<Bytes, String> {
+    if offset > U256::from(signatures.len()) {
+        return Err(format!("contract signature offset {offset} is out of bounds"));
+    }
+    let offset = offset.as_usize();
+    if offset < static_length {
+        return Err(format!("contract signature offset {offset} points inside static part"));
+    }
+    if offset + 32 > signatures.len() {
+        return Err(format!("contract signature length at {offset} is out of bounds"));
+    }
+    let length = U256::from_big_endian(&signatures[offset..offset + 32]);
+    if length > U256::from(signatures.len() - offset - 32) {
+        return Err(format!("contract signature of length {length} is out of bounds"));
+    }
+    let start = offset + 32;
+    Ok(Bytes::from(signatures[start..start + length.as_usize()].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::core::k256::ecdsa::SigningKey;
+    use ethers::core::k256::SecretKey;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::transaction::eip712::{Eip712, TypedData};
+    use ethers::utils::{hash_message, hex};
+
+    use super::*;
+
+    const SAFE: &str = "0x1234567890123456789012345678901234567890";
+
+    fn safe_tx() -> SafeTx {
+        SafeTx {
+            to: Address::repeat_byte(0x11),
+            value: U256::from(1_000),
+            data: Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]),
+            operation: 0,
+            safe_tx_gas: U256::from(50_000),
+            base_gas: U256::from(30_000),
+            gas_price: U256::from(1_000_000_000u64),
+            gas_token: Address::zero(),
+            refund_receiver: Address::repeat_byte(0x22),
+            nonce: U256::from(7),
+        }
+    }
+
+    fn wallet(byte: u8) -> LocalWallet {
+        LocalWallet::from(SigningKey::from(SecretKey::from_be_bytes(&[byte; 32]).unwrap()))
+    }
+
+    fn packed(signature: &Signature) -> Vec<u8> {
+        let mut packed = vec![0u8; SIGNATURE_LENGTH];
+        signature.r.to_big_endian(&mut packed[..32]);
+        signature.s.to_big_endian(&mut packed[32..64]);
+        packed[64] = signature.v as u8;
+        packed
+    }
+
+    fn owner_word(owner: Address, s: U256, v: u8) -> Vec<u8> {
+        let mut packed = vec![0u8; SIGNATURE_LENGTH];
+        packed[12..32].copy_from_slice(owner.as_bytes());
+        s.to_big_endian(&mut packed[32..64]);
+        packed[64] = v;
+        packed
+    }
+
+    #[test]
+    fn type_hashes_match_the_safe_contracts() {
+        assert_eq!(hex::encode(keccak256(SAFE_TX_TYPE)), "bb8310d486368db6bd6f849402fdd73ad53d316b5a4b2644ad6efe0f941286d8");
+        assert_eq!(hex::encode(keccak256(DOMAIN_WITH_CHAIN_ID_TYPE)), "47e79534a245952e8b16893a336b85a3d9ea9fa8c573f3d803afb92a79469218");
+        assert_eq!(hex::encode(keccak256(DOMAIN_TYPE)), "035aff83d86937d35b32e04f0ddc6ff469290eef2f1b692d8a815c89404d4749");
+    }
+
+    #[test]
+    fn hash_matches_typed_data() {
+        let safe = SAFE.parse::<Address>().unwrap();
+        let chain_id = U256::from(5);
+        for with_chain_id in [true, false] {
+            let domain_separator = domain_separator(safe, chain_id, with_chain_id);
+            let typed_data: TypedData = serde_json::from_value(safe_tx().typed_data(safe, chain_id, domain_separator)).unwrap();
+
+            assert_eq!(typed_data.domain.separator(), domain_separator);
+            assert_eq!(H256::from(typed_data.encode_eip712().unwrap()), safe_tx().hash(domain_separator));
+        }
+    }
+
+    #[test]
+    fn domain_separator_depends_on_chain_id_only_with_it() {
+        let safe = SAFE.parse::<Address>().unwrap();
+
+        assert_ne!(domain_separator(safe, U256::from(1), true), domain_separator(safe, U256::from(5), true));
+        assert_eq!(domain_separator(safe, U256::from(1), false), domain_separator(safe, U256::from(5), false));
+    }
+
+    #[test]
+    fn chain_id_is_in_the_domain_from_1_3_0() {
+        assert!(!domain_has_chain_id("1.0.0"));
+        assert!(!domain_has_chain_id("1.1.1"));
+        assert!(!domain_has_chain_id("1.2.0"));
+        assert!(domain_has_chain_id("1.3.0"));
+        assert!(domain_has_chain_id("1.4.1"));
+        assert!(!domain_has_chain_id("unknown"));
+    }
+
+    #[test]
+    fn splits_every_signature_type() {
+        let tx_hash = safe_tx().hash(domain_separator(SAFE.parse().unwrap(), U256::one(), true));
+        let (ecdsa_owner, eth_sign_owner) = (wallet(1), wallet(2));
+        let (approved_owner, contract_owner) = (Address::repeat_byte(0x33), Address::repeat_byte(0x44));
+
+        let mut eth_sign = packed(&eth_sign_owner.sign_hash(hash_message(tx_hash)));
+        eth_sign[64] += 4;
+        let contract_data = vec![0xab; 40];
+        let mut signatures = [
+            packed(&ecdsa_owner.sign_hash(tx_hash)),
+            eth_sign,
+            owner_word(approved_owner, U256::zero(), 1),
+            owner_word(contract_owner, U256::from(4 * SIGNATURE_LENGTH), 0),
+        ].concat();
+        signatures.extend_from_slice(&encode(&[Token::Uint(U256::from(contract_data.len()))]));
+        signatures.extend_from_slice(&contract_data);
+
+        let split = split_signatures(&signatures, 4, tx_hash).unwrap();
+        assert!(matches!(split[0], SafeSignature::Ecdsa(owner) if owner == ecdsa_owner.address()));
+        assert!(matches!(split[1], SafeSignature::EthSign(owner) if owner == eth_sign_owner.address()));
+        assert!(matches!(split[2], SafeSignature::ApprovedHash(owner) if owner == approved_owner));
+        assert!(matches!(&split[3], SafeSignature::Contract(owner, data) if *owner == contract_owner && data.to_vec() == contract_data));
+    }
+
+    #[test]
+    fn only_splits_up_to_the_threshold() {
+        let signatures = [owner_word(Address::repeat_byte(1), U256::zero(), 1), owner_word(Address::repeat_byte(2), U256::zero(), 1)].concat();
+
+        assert_eq!(split_signatures(&signatures, 1, H256::zero()).unwrap().len(), 1);
+        assert!(matches!(split_signatures(&signatures, 3, H256::zero()), Err(SafeError::BadSignatures(_))));
+    }
+
+    #[test]
+    fn refuses_bad_signatures() {
+        let unsupported_v = owner_word(Address::repeat_byte(1), U256::zero(), 2);
+        assert!(matches!(split_signatures(&unsupported_v, 1, H256::zero()), Err(SafeError::BadSignatures(_))));
+
+        let inside_static_part = owner_word(Address::repeat_byte(1), U256::from(32), 0);
+        assert!(matches!(split_signatures(&inside_static_part, 1, H256::zero()), Err(SafeError::BadSignatures(_))));
+
+        let mut too_long = owner_word(Address::repeat_byte(1), U256::from(SIGNATURE_LENGTH), 0);
+        too_long.extend_from_slice(&encode(&[Token::Uint(U256::from(100))]));
+        assert!(matches!(split_signatures(&too_long, 1, H256::zero()), Err(SafeError::BadSignatures(_))));
+    }
+}
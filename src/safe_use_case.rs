@@ -1,53 +1,217 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use crate::safe::{Safe, SafeError, SafeResponse};
+use crate::safe::{AuthNonce, BatchCall, ModuleChange, OwnerChange, RelayerHealth, RelayStatus, Safe, SafeError, SafeFeeQuote, SafeModules, SafeResponse, SafeSetup, SafeState, SafeTxHash, SafeTxPayload};
+use crate::safe_auth::SiweAuth;
+use crate::safe_sponsor::Sponsee;
+use crate::safe_tenant::Tenant;
 use crate::SafeInfo;
 
-type SafeType = Arc<dyn Safe + Send + Sync + 'static>;
+pub(crate) type SafeType = Arc<dyn Safe + Send + Sync + 'static>;
 
+/// Serves every configured chain, requests without a chain id go to the default one.
 #[derive(Clone)]
 pub(crate) struct SafeUseCase {
-    safe: SafeType,
+    chains: Arc<HashMap<u64, SafeType>>,
+    default_chain_id: u64,
+    auth: Arc<SiweAuth>,
 }
 
 impl SafeUseCase {
-    pub(crate) fn new(safe: SafeType) -> Self {
+    pub(crate) fn new(chains: HashMap<u64, SafeType>, default_chain_id: u64, auth: SiweAuth) -> Self {
         Self {
-            safe
+            chains: Arc::new(chains),
+            default_chain_id,
+            auth: Arc::new(auth),
         }
     }
 
-    pub(crate) async fn info(&self, user_address: &str) -> Result<SafeInfo, SafeError> {
-        self.safe.info(user_address).await
+    fn safe(&self, chain_id: Option<u64>) -> Result<&SafeType, SafeError> {
+        let chain_id = chain_id.unwrap_or(self.default_chain_id);
+        self.chains.get(&chain_id).ok_or(SafeError::UnsupportedChain(chain_id))
     }
 
-    pub(crate) async fn deploy(&self, user_address: &str) -> Result<SafeResponse, SafeError> {
-        self.safe.deploy(user_address).await
+    pub(crate) fn auth_nonce(&self) -> AuthNonce {
+        AuthNonce {
+            nonce: self.auth.nonce(),
+        }
+    }
+
+    pub(crate) async fn info(&self, chain_id: Option<u64>, user_address: &str, setup: &SafeSetup) -> Result<SafeInfo, SafeError> {
+        self.safe(chain_id)?.info(user_address, setup).await
+    }
+
+    pub(crate) async fn list(&self, chain_id: Option<u64>, user_address: &str, setup: &SafeSetup, from: u64, count: u64) -> Result<Vec<SafeInfo>, SafeError> {
+        self.safe(chain_id)?.list(user_address, setup, from, count).await
+    }
+
+    pub(crate) async fn state(&self, chain_id: Option<u64>, user_address: &str, setup: &SafeSetup) -> Result<SafeState, SafeError> {
+        self.safe(chain_id)?.state(user_address, setup).await
+    }
+
+    pub(crate) async fn modules(&self,
+                                chain_id: Option<u64>,
+                                user_address: &str,
+                                setup: &SafeSetup,
+                                start: Option<&str>,
+                                count: u64) -> Result<SafeModules, SafeError> {
+        self.safe(chain_id)?.modules(user_address, setup, start, count).await
+    }
+
+    /// Checks the sponsee proves to own `user_address`, sponsored deployments are paid for on its behalf.
+    fn sign_in(&self, chain_id: Option<u64>, user_address: &str, sponsee: &Sponsee) -> Result<(), SafeError> {
+        match (&sponsee.message, &sponsee.signature) {
+            (Some(message), Some(signature)) => {
+                self.auth.verify(user_address, chain_id.unwrap_or(self.default_chain_id), message, signature)
+            }
+            _ => Err(SafeError::Unauthorized("signed sign-in message is required".to_string())),
+        }
+    }
+
+    /// Deploys on the relayer's expense for a caller proving to own `user_address`,
+    /// within the tenant's sponsorship quotas.
+    pub(crate) async fn deploy(&self,
+                               chain_id: Option<u64>,
+                               tenant: &Tenant,
+                               user_address: &str,
+                               setup: &SafeSetup,
+                               sponsee: &Sponsee) -> Result<SafeResponse, SafeError> {
+        let safe = self.safe(chain_id)?;
+        self.sign_in(chain_id, user_address, sponsee)?;
+        tenant.sponsorship.reserve(user_address, sponsee)?;
+        let response = safe.deploy(user_address, setup).await;
+        if response.is_err() {
+            tenant.sponsorship.release(user_address, sponsee);
+        }
+        response
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn tx_hash(&self,
+                                chain_id: Option<u64>,
+                                user_address: &str,
+                                setup: &SafeSetup,
+                                to: &str,
+                                value: &str,
+                                data: Vec<u8>,
+                                operation: u8,
+                                calls: &[BatchCall],
+                                safe_tx_gas: &str,
+                                base_gas: &str,
+                                gas_price: &str,
+                                gas_token: &str,
+                                refund_receiver: &str) -> Result<SafeTxHash, SafeError> {
+        let safe = self.safe(chain_id)?;
+        safe.tx_hash(user_address,
+                     setup,
+                     to,
+                     value,
+                     data,
+                     operation,
+                     calls,
+                     safe_tx_gas,
+                     base_gas,
+                     gas_price,
+                     gas_token,
+                     refund_receiver).await
+    }
+
+    /// Relays a transaction of a deployed Safe. With `deploy_if_needed` a Safe not deployed yet
+    /// is deployed first, sponsored and signed in for like a deployment.
     #[allow(clippy::too_many_arguments)]
     pub(crate) async fn exec(&self,
+                             chain_id: Option<u64>,
+                             deploy_if_needed: Option<(&Tenant, &Sponsee)>,
                              user_address: &str,
+                             setup: &SafeSetup,
                              to: &str,
                              value: &str,
                              data: Vec<u8>,
                              operation: u8,
+                             calls: &[BatchCall],
                              safe_tx_gas: &str,
                              base_gas: &str,
                              gas_price: &str,
                              gas_token: &str,
                              refund_receiver: &str,
                              signatures: Vec<u8>) -> Result<SafeResponse, SafeError> {
-        self.safe.exec(user_address,
-                       to,
-                       value,
-                       data,
-                       operation,
-                       safe_tx_gas,
-                       base_gas,
-                       gas_price,
-                       gas_token,
-                       refund_receiver,
-                       signatures).await
+        let safe = self.safe(chain_id)?;
+        if let Some((tenant, sponsee)) = deploy_if_needed {
+            if !safe.info(user_address, setup).await?.is_deployed {
+                // the owners may be anyone, only sign-in ties the sponsorship to `user_address`
+                self.sign_in(chain_id, user_address, sponsee)?;
+                tenant.sponsorship.reserve(user_address, sponsee)?;
+                let response = safe.deploy_exec(user_address,
+                                                setup,
+                                                to,
+                                                value,
+                                                data,
+                                                operation,
+                                                calls,
+                                                safe_tx_gas,
+                                                base_gas,
+                                                gas_price,
+                                                gas_token,
+                                                refund_receiver,
+                                                signatures).await;
+                if response.is_err() {
+                    tenant.sponsorship.release(user_address, sponsee);
+                }
+                return response;
+            }
+        }
+        safe.exec(user_address,
+                  setup,
+                  to,
+                  value,
+                  data,
+                  operation,
+                  calls,
+                  safe_tx_gas,
+                  base_gas,
+                  gas_price,
+                  gas_token,
+                  refund_receiver,
+                  signatures).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn estimate(&self,
+                                 chain_id: Option<u64>,
+                                 user_address: &str,
+                                 setup: &SafeSetup,
+                                 to: &str,
+                                 value: &str,
+                                 data: Vec<u8>,
+                                 operation: u8,
+                                 calls: &[BatchCall],
+                                 gas_token: &str) -> Result<SafeFeeQuote, SafeError> {
+        self.safe(chain_id)?.estimate(user_address, setup, to, value, data, operation, calls, gas_token).await
+    }
+
+    pub(crate) async fn owner_tx(&self,
+                                 chain_id: Option<u64>,
+                                 user_address: &str,
+                                 setup: &SafeSetup,
+                                 change: &OwnerChange,
+                                 gas_token: &str) -> Result<SafeTxPayload, SafeError> {
+        self.safe(chain_id)?.owner_tx(user_address, setup, change, gas_token).await
+    }
+
+    pub(crate) async fn module_tx(&self,
+                                  chain_id: Option<u64>,
+                                  user_address: &str,
+                                  setup: &SafeSetup,
+                                  change: &ModuleChange,
+                                  gas_token: &str) -> Result<SafeTxPayload, SafeError> {
+        self.safe(chain_id)?.module_tx(user_address, setup, change, gas_token).await
+    }
+
+    pub(crate) async fn relay(&self, chain_id: Option<u64>, relay_id: &str) -> Result<RelayStatus, SafeError> {
+        self.safe(chain_id)?.relay(relay_id).await
+    }
+
+    pub(crate) async fn health(&self, chain_id: Option<u64>) -> Result<RelayerHealth, SafeError> {
+        self.safe(chain_id)?.health().await
     }
 }
\ No newline at end of file